@@ -1,8 +1,9 @@
 /* memory.x - Linker script for the STM32F103C8T6 */
 MEMORY
 {
-  /* Flash memory begins at 0x80000000 and has a size of 64kB*/
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* Flash memory begins at 0x80000000 and has a size of 64kB, the last 1kB
+     page is reserved for persistent data (see src/storage.rs) */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 63K
   /* RAM begins at 0x20000000 and has a size of 20kB*/
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
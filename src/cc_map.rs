@@ -0,0 +1,170 @@
+// sequencer parameters which can be controlled by a MIDI CC
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Parameter {
+    Tempo,
+    GateLength,
+    TrackLength,
+    Transpose,
+    RandomDensity,
+    CurrentTrack,
+}
+
+pub const PARAMETERS_COUNT: usize = 6;
+
+const PARAMETERS: [Parameter; PARAMETERS_COUNT] = [
+    Parameter::Tempo,
+    Parameter::GateLength,
+    Parameter::TrackLength,
+    Parameter::Transpose,
+    Parameter::RandomDensity,
+    Parameter::CurrentTrack,
+];
+
+// marker for a parameter without assigned control
+const UNASSIGNED: u8 = 0xFF;
+
+impl Parameter {
+    // parameter at a given index, used to select a parameter from a step key
+    pub fn from_index(index: usize) -> Option<Parameter> {
+        PARAMETERS.get(index).copied()
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Parameter::Tempo => 0,
+            Parameter::GateLength => 1,
+            Parameter::TrackLength => 2,
+            Parameter::Transpose => 3,
+            Parameter::RandomDensity => 4,
+            Parameter::CurrentTrack => 5,
+        }
+    }
+}
+
+// scale a CC value (0-127) to the min..=max range
+pub fn scale(value: u8, min: i32, max: i32) -> i32 {
+    min + (value.min(127) as i32 * (max - min) + 63) / 127
+}
+
+// mapping of MIDI CC numbers to sequencer parameters, a parameter is
+// learned by holding its key while moving a control
+#[derive(Copy, Clone, Debug)]
+pub struct CcMap {
+    controls: [u8; PARAMETERS_COUNT],
+    learning: Option<Parameter>,
+}
+
+impl Default for CcMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CcMap {
+    pub fn new() -> CcMap {
+        CcMap {
+            controls: [UNASSIGNED; PARAMETERS_COUNT],
+            learning: None,
+        }
+    }
+
+    // return parameter assigned to a given control
+    pub fn find(&self, control: u8) -> Option<Parameter> {
+        self.controls
+            .iter()
+            .position(|c| *c == control)
+            .and_then(Parameter::from_index)
+    }
+
+    pub fn get_control(&self, parameter: Parameter) -> Option<u8> {
+        match self.controls[parameter.index()] {
+            UNASSIGNED => None,
+            control => Some(control),
+        }
+    }
+
+    // assign control to a parameter, a control drives a single parameter so
+    // any previous assignment is removed. Return true if the map changed
+    pub fn assign(&mut self, parameter: Parameter, control: u8) -> bool {
+        let control = control & 0x7F;
+        if self.get_control(parameter) == Some(control) {
+            return false;
+        }
+        for c in self.controls.iter_mut() {
+            if *c == control {
+                *c = UNASSIGNED;
+            }
+        }
+        self.controls[parameter.index()] = control;
+        true
+    }
+
+    pub fn learn(&mut self, parameter: Parameter) -> &mut Self {
+        self.learning = Some(parameter);
+        self
+    }
+
+    pub fn stop_learning(&mut self) -> &mut Self {
+        self.learning = None;
+        self
+    }
+
+    pub fn get_learning(&self) -> Option<Parameter> {
+        self.learning
+    }
+
+    pub fn to_bytes(&self) -> [u8; PARAMETERS_COUNT] {
+        self.controls
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> CcMap {
+        let mut map = CcMap::new();
+        for (i, control) in bytes.iter().take(PARAMETERS_COUNT).enumerate() {
+            if *control <= 0x7F {
+                map.controls[i] = *control;
+            }
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assign_controls() {
+        let mut map = CcMap::new();
+        assert!(map.assign(Parameter::Tempo, 74));
+        assert!(!map.assign(Parameter::Tempo, 74));
+        assert_eq!(map.find(74), Some(Parameter::Tempo));
+
+        // a control drives a single parameter
+        assert!(map.assign(Parameter::Transpose, 74));
+        assert_eq!(map.get_control(Parameter::Tempo), None);
+        assert_eq!(map.find(74), Some(Parameter::Transpose));
+
+        // controls are 7 bits
+        assert!(!map.assign(Parameter::Transpose, 74 | 0x80));
+        assert!(map.assign(Parameter::GateLength, 74 | 0x80));
+        assert_eq!(map.get_control(Parameter::GateLength), Some(74));
+        assert_eq!(map.get_control(Parameter::Transpose), None);
+        assert_eq!(map.to_bytes().iter().filter(|c| **c == 74).count(), 1);
+    }
+
+    #[test]
+    fn map_bytes() {
+        let mut map = CcMap::new();
+        map.assign(Parameter::CurrentTrack, 0);
+        map.assign(Parameter::Tempo, 127);
+        let loaded = CcMap::from_bytes(&map.to_bytes());
+        assert_eq!(loaded.to_bytes(), map.to_bytes());
+        assert_eq!(
+            CcMap::from_bytes(&[0x80, 3]).to_bytes()[..2],
+            [UNASSIGNED, 3]
+        );
+        assert_eq!(scale(0, 40, 240), 40);
+        assert_eq!(scale(127, 40, 240), 240);
+        assert_eq!(scale(200, -24, 24), 24);
+    }
+}
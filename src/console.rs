@@ -0,0 +1,938 @@
+use core::fmt::{self, Write};
+use core::str::FromStr;
+
+use heapless::{Deque, String, Vec};
+
+use crate::constants::*;
+use crate::midi::SysexBuffer;
+use crate::notation::{self, Notation, ParseError};
+use crate::settings::Settings;
+use crate::sysex::{self, Sysex, SysexError, SYSEX_END, SYSEX_MAX_SIZE, SYSEX_START};
+use crate::track::{Condition, Step, Track, TrackMode};
+
+// line based command console, track numbers start at 1:
+//
+//   tempo <bpm>                      set the tempo
+//   gate <percent>                   set the gate length
+//   swing <percent>                  set the delay of the even steps
+//   track <n>                        select the current track
+//   track <n> length <steps>         set the length of a track
+//   track <n> transpose <semitones>  transpose a track
+//   track <n> mode gate|cv|velocity  set the mode of a track
+//   track <n> glide <ms>             set the glide time of the sliding steps
+//   track <n> swing <percent>|off    set or clear the swing of a track
+//   pattern <n>                      print the pattern of a track
+//   pattern <n> "<notation>"         set the pattern of a track
+//   step <n> <step> <notation> [gate <percent>] [ratchet <count>]
+//        [prob <percent>] [cond <condition>] [slide] [vel <velocity>]
+//        [nudge <percent>]
+//                                    set a single step of a track, its gate
+//                                    length, number of gates, probability,
+//                                    condition, glide from the previous
+//                                    note, velocity and offset from the grid
+//   randomize <n> <percent>          randomize a track with a gate density
+//   play, stop                       start or stop all the tracks
+//   fill on|off                      play the steps with a fill condition
+//   dump [<n>]                       print a project or track SysEx dump
+//                                    as load commands
+//   load <hex>                       load a chunk of a SysEx dump, the dump
+//                                    is loaded once its last chunk is received
+//   status                           print the sequencer state
+
+pub const HELP: &str = "commands: tempo <bpm>, gate <percent>, swing <percent>, \
+track <n> [length <steps> | transpose <semitones> | mode gate|cv|velocity | glide <ms> | \
+swing <percent>|off], pattern <n> [\"<notation>\"], \
+step <n> <step> <notation> [gate <percent>] [ratchet <count>] [prob <percent>] \
+[cond first|pre|!pre|fill|!fill|<a>:<b>] [slide] [vel <0-255>] [nudge <-50-50>], randomize <n> <percent>, play, stop, fill on|off, \
+dump [<n>], load <hex>, status";
+
+// longest line, dumps are too long for a single line and are loaded in
+// chunks
+pub const LINE_SIZE: usize = 128;
+// most bytes of a load command
+pub const CHUNK_SIZE: usize = LINE_SIZE / 2;
+// bytes printed on each load command of a dump
+const DUMP_LINE_BYTES: usize = 32;
+// text of a project dump, its bytes printed as " XX" on load commands
+const DUMP_TEXT_SIZE: usize = SYSEX_MAX_SIZE * 3
+    + (SYSEX_MAX_SIZE + DUMP_LINE_BYTES - 1) / DUMP_LINE_BYTES * "load\r\n".len();
+// replies waiting to be sent, a whole project dump fits
+pub const OUTPUT_SIZE: usize = DUMP_TEXT_SIZE + 2 * LINE_SIZE;
+
+pub type Line = String<LINE_SIZE>;
+pub type Chunk = Vec<u8, CHUNK_SIZE>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TrackSetting {
+    Length(usize),
+    Transpose(i8),
+    Mode(TrackMode),
+    Glide(u16),
+    Swing(Option<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Tempo(u16),
+    Gate(u8),
+    Swing(u8),
+    Track(usize, Option<TrackSetting>),
+    Pattern(usize, Option<[Step; STEPS_COUNT]>),
+    Step(usize, usize, Step),
+    Randomize(usize, u8),
+    Play,
+    Stop,
+    Fill(bool),
+    Dump(Option<usize>),
+    Load(Chunk),
+    Status,
+    Help,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument,
+    TooManyArguments,
+    LineTooLong,
+    Pattern(ParseError),
+    Sysex(SysexError),
+    // the reply does not fit in the output
+    OutputFull,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownCommand => write!(f, "unknown command, type help"),
+            Error::MissingArgument => write!(f, "missing argument"),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+            Error::TooManyArguments => write!(f, "too many arguments"),
+            Error::LineTooLong => write!(f, "line too long"),
+            Error::Pattern(error) => write!(f, "{}", error),
+            Error::Sysex(error) => write!(f, "invalid dump ({:?})", error),
+            Error::OutputFull => write!(f, "output full"),
+        }
+    }
+}
+
+impl From<fmt::Error> for Error {
+    fn from(_: fmt::Error) -> Self {
+        Error::OutputFull
+    }
+}
+
+// parse a command line
+pub fn parse(line: &str) -> Result<Command, Error> {
+    let mut args = line.split_whitespace();
+    let command = match args.next().ok_or(Error::MissingArgument)? {
+        "tempo" => Command::Tempo(number(args.next(), MIN_BPM, MAX_BPM)?),
+        "gate" => Command::Gate(number(args.next(), 1, 100)?),
+        "swing" => Command::Swing(number(args.next(), 0, MAX_SWING)?),
+        "track" => {
+            let index = track_index(args.next())?;
+            let setting = match args.next() {
+                None => None,
+                Some("length") => Some(TrackSetting::Length(number(args.next(), 1, STEPS_COUNT)?)),
+                Some("transpose") => Some(TrackSetting::Transpose(number(
+                    args.next(),
+                    -MAX_TRANSPOSE,
+                    MAX_TRANSPOSE,
+                )?)),
+                Some("mode") => Some(TrackSetting::Mode(
+                    match args.next().ok_or(Error::MissingArgument)? {
+                        "gate" => TrackMode::GATE,
+                        "cv" => TrackMode::CV,
+                        "velocity" => TrackMode::VELOCITY,
+                        _ => return Err(Error::InvalidArgument),
+                    },
+                )),
+                Some("glide") => Some(TrackSetting::Glide(number(args.next(), 0, MAX_GLIDE_MS)?)),
+                Some("swing") => Some(TrackSetting::Swing(match args.next() {
+                    Some("off") => None,
+                    arg => Some(number(arg, 0, MAX_SWING)?),
+                })),
+                Some(_) => return Err(Error::InvalidArgument),
+            };
+            Command::Track(index, setting)
+        }
+        "pattern" => return parse_pattern(line),
+        "step" => {
+            let index = track_index(args.next())?;
+            let step = number(args.next(), 1, STEPS_COUNT)? - 1;
+            let token = args.next().ok_or(Error::MissingArgument)?;
+            // position of the token in the line, to report notation errors
+            let offset = token.as_ptr() as usize - line.as_ptr() as usize;
+            let mut steps = notation::parse(token).map_err(|error| {
+                Error::Pattern(ParseError {
+                    position: offset + error.position,
+                    ..error
+                })
+            })?;
+            while let Some(option) = args.next() {
+                match option {
+                    "gate" => steps[0].gate_length = number(args.next(), 1, 100)?,
+                    "ratchet" => steps[0].ratchets = number(args.next(), 1, MAX_RATCHETS)?,
+                    "prob" => steps[0].probability = number(args.next(), 0, 100)?,
+                    "cond" => steps[0].condition = condition(args.next())?,
+                    "slide" => steps[0].slide = true,
+                    "vel" => steps[0].velocity = number(args.next(), 0, 255)?,
+                    "nudge" => {
+                        steps[0].nudge = number(args.next(), -(MAX_NUDGE as i8), MAX_NUDGE as i8)?
+                    }
+                    _ => return Err(Error::InvalidArgument),
+                }
+            }
+            Command::Step(index, step, steps[0])
+        }
+        "randomize" => Command::Randomize(track_index(args.next())?, number(args.next(), 0, 100)?),
+        "play" => Command::Play,
+        "stop" => Command::Stop,
+        "fill" => Command::Fill(match args.next().ok_or(Error::MissingArgument)? {
+            "on" => true,
+            "off" => false,
+            _ => return Err(Error::InvalidArgument),
+        }),
+        "dump" => Command::Dump(args.next().map(|arg| track_index(Some(arg))).transpose()?),
+        "load" => {
+            let mut chunk = Chunk::new();
+            for arg in args.by_ref() {
+                let byte = match arg.len() {
+                    1 | 2 => u8::from_str_radix(arg, 16).map_err(|_| Error::InvalidArgument)?,
+                    _ => return Err(Error::InvalidArgument),
+                };
+                chunk.push(byte).map_err(|_| Error::InvalidArgument)?;
+            }
+            if chunk.is_empty() {
+                return Err(Error::MissingArgument);
+            }
+            Command::Load(chunk)
+        }
+        "status" => Command::Status,
+        "help" => Command::Help,
+        _ => return Err(Error::UnknownCommand),
+    };
+
+    match args.next() {
+        Some(_) => Err(Error::TooManyArguments),
+        None => Ok(command),
+    }
+}
+
+// pattern <n> ["<notation>"], notation errors are reported with their
+// position in the line
+fn parse_pattern(line: &str) -> Result<Command, Error> {
+    let (args, notation) = match line.find('"') {
+        Some(start) => (&line[..start], Some(start + 1)),
+        None => (line, None),
+    };
+    let mut args = args.split_whitespace().skip(1);
+    let index = track_index(args.next())?;
+    if args.next().is_some() {
+        return Err(Error::TooManyArguments);
+    }
+
+    let start = match notation {
+        Some(start) => start,
+        None => return Ok(Command::Pattern(index, None)),
+    };
+    let end = match line[start..].find('"') {
+        Some(end) => start + end,
+        None => return Err(Error::InvalidArgument),
+    };
+    if !line[end + 1..].trim().is_empty() {
+        return Err(Error::TooManyArguments);
+    }
+    let pattern = notation::parse(&line[start..end]).map_err(|error| {
+        Error::Pattern(ParseError {
+            position: start + error.position,
+            ..error
+        })
+    })?;
+    Ok(Command::Pattern(index, Some(pattern)))
+}
+
+fn number<T: FromStr + PartialOrd>(arg: Option<&str>, min: T, max: T) -> Result<T, Error> {
+    let value: T = arg
+        .ok_or(Error::MissingArgument)?
+        .parse()
+        .map_err(|_| Error::InvalidArgument)?;
+    if value < min || value > max {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(value)
+}
+
+// trig condition of a step: always, first, pre, !pre, fill, !fill or <a>:<b>
+// for the a-th cycle of every b cycles
+fn condition(arg: Option<&str>) -> Result<Condition, Error> {
+    let condition = match arg.ok_or(Error::MissingArgument)? {
+        "always" => Condition::Always,
+        "first" => Condition::First,
+        "pre" => Condition::Previous,
+        "!pre" => Condition::NotPrevious,
+        "fill" => Condition::Fill,
+        "!fill" => Condition::NotFill,
+        cycle => {
+            let (a, b) = cycle.split_once(':').ok_or(Error::InvalidArgument)?;
+            let a = number(Some(a), 1, MAX_CONDITION_CYCLES)?;
+            let b = number(Some(b), 2, MAX_CONDITION_CYCLES)?;
+            Condition::cycle(a, b).ok_or(Error::InvalidArgument)?
+        }
+    };
+    Ok(condition)
+}
+
+// track numbers start at 1 on the console
+fn track_index(arg: Option<&str>) -> Result<usize, Error> {
+    number(arg, 1, TRACKS_COUNT).map(|track| track - 1)
+}
+
+// parse and apply a console command line, then reply with the outcome. An
+// error is returned when the reply does not fit in the output
+pub fn execute(
+    line: &str,
+    tracks: &mut [Track; TRACKS_COUNT],
+    current_track: &mut usize,
+    settings: &mut Settings,
+    loader: &mut DumpLoader,
+    out: &mut impl fmt::Write,
+) -> fmt::Result {
+    match parse(line)
+        .and_then(|command| run_command(command, tracks, current_track, settings, loader, out))
+    {
+        Ok(()) => writeln!(out, "ok"),
+        Err(Error::OutputFull) => Err(fmt::Error),
+        Err(error) => writeln!(out, "error: {}", error),
+    }
+}
+
+// execute a command line replying on the output queue, a reply which does
+// not fit is replaced by an error
+pub fn execute_queued(
+    line: &str,
+    tracks: &mut [Track; TRACKS_COUNT],
+    current_track: &mut usize,
+    settings: &mut Settings,
+    loader: &mut DumpLoader,
+    out: &mut Output,
+) {
+    let len = out.len();
+    if execute(line, tracks, current_track, settings, loader, out).is_err() {
+        out.truncate(len);
+        writeln!(out, "error: {}", Error::OutputFull).ok();
+    }
+}
+
+// apply a console command, replies are written to the output
+fn run_command(
+    command: Command,
+    tracks: &mut [Track; TRACKS_COUNT],
+    current_track: &mut usize,
+    settings: &mut Settings,
+    loader: &mut DumpLoader,
+    out: &mut impl fmt::Write,
+) -> Result<(), Error> {
+    match command {
+        Command::Tempo(bpm) => {
+            settings.set_bpm(bpm);
+        }
+        Command::Gate(gate_length) => {
+            settings.set_gate_length(gate_length);
+        }
+        Command::Swing(swing) => {
+            settings.set_swing(swing);
+        }
+        Command::Track(index, None) => *current_track = index,
+        Command::Track(index, Some(setting)) => {
+            let track = &mut tracks[index];
+            match setting {
+                TrackSetting::Length(length) => track.set_track_length(length),
+                TrackSetting::Transpose(semitones) => track.set_transpose(semitones),
+                TrackSetting::Mode(mode) => track.set_mode(mode),
+                TrackSetting::Glide(glide) => track.set_glide(glide),
+                TrackSetting::Swing(swing) => track.set_swing(swing),
+            };
+        }
+        Command::Pattern(index, Some(pattern)) => {
+            tracks[index].set_pattern(pattern);
+        }
+        Command::Pattern(index, None) => {
+            write_pattern(out, index, &mut tracks[index])?;
+            writeln!(out)?;
+        }
+        Command::Step(index, step, value) => {
+            tracks[index].set_step(step, value);
+        }
+        Command::Randomize(index, density) => {
+            tracks[index].randomize(density as f64 / 100.0);
+        }
+        Command::Fill(fill) => {
+            for track in tracks.iter_mut() {
+                track.set_fill(fill);
+            }
+        }
+        Command::Play => {
+            for track in tracks.iter_mut() {
+                track.play();
+            }
+        }
+        Command::Stop => {
+            for track in tracks.iter_mut() {
+                track.stop();
+            }
+        }
+        Command::Dump(index) => {
+            let mut sysex = [0; SYSEX_MAX_SIZE];
+            let len = match index {
+                Some(index) => sysex::encode_track(index, &tracks[index], &mut sysex),
+                None => sysex::encode_project(tracks, &mut sysex),
+            };
+            // printed as commands loading the dump back
+            for chunk in sysex[..len].chunks(DUMP_LINE_BYTES) {
+                write!(out, "load")?;
+                for byte in chunk {
+                    write!(out, " {:02X}", byte)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        Command::Load(chunk) => {
+            if let Some(sysex) = loader.push(&chunk)? {
+                match sysex::decode(sysex, tracks) {
+                    Ok(Sysex::TrackLoaded(_)) | Ok(Sysex::ProjectLoaded) => {}
+                    // requests are answered over MIDI only
+                    Ok(_) => return Err(Error::InvalidArgument),
+                    Err(error) => return Err(Error::Sysex(error)),
+                }
+            }
+        }
+        Command::Status => {
+            writeln!(
+                out,
+                "tempo {}, gate {}%, swing {}%, current track {}",
+                settings.get_bpm(),
+                settings.get_gate_length(),
+                settings.get_swing(),
+                *current_track + 1
+            )?;
+            for (index, track) in tracks.iter_mut().enumerate() {
+                write!(
+                    out,
+                    "track {}: {}, {}, length {}, transpose {}, glide {}ms",
+                    index + 1,
+                    if track.is_playing() {
+                        "playing"
+                    } else {
+                        "stopped"
+                    },
+                    match track.get_mode() {
+                        TrackMode::GATE => "gate",
+                        TrackMode::CV => "cv",
+                        TrackMode::VELOCITY => "velocity",
+                    },
+                    track.get_track_length(),
+                    track.get_transpose(),
+                    track.get_glide()
+                )?;
+                // tracks without swing use the swing of the settings
+                match track.get_swing() {
+                    Some(swing) => writeln!(out, ", swing {}%", swing),
+                    None => writeln!(out),
+                }?;
+                write_pattern(out, index, track)?;
+                writeln!(out)?;
+            }
+        }
+        Command::Help => {
+            writeln!(out, "{}", HELP)?;
+        }
+    }
+    Ok(())
+}
+
+// write the pattern of a track as a console command
+fn write_pattern(out: &mut impl fmt::Write, index: usize, track: &mut Track) -> fmt::Result {
+    let pattern = track.get_pattern();
+    let gates_only = track.get_mode() != TrackMode::CV;
+    write!(
+        out,
+        "pattern {} \"{}\"",
+        index + 1,
+        Notation::new(&pattern[..track.get_track_length()], gates_only)
+    )
+}
+
+// assemble the chunks of a dump received by load commands. A chunk starting
+// with F0 starts a dump, which is complete once a chunk ends with F7
+#[derive(Clone, Debug, Default)]
+pub struct DumpLoader {
+    sysex: SysexBuffer,
+}
+
+impl DumpLoader {
+    // add a chunk, return the dump once complete
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<&[u8]>, Error> {
+        if chunk.first() == Some(&SYSEX_START) {
+            self.sysex.clear();
+        } else if self.sysex.is_empty() || self.sysex.last() == Some(&SYSEX_END) {
+            // not the continuation of a dump
+            return Err(Error::Sysex(SysexError::Malformed));
+        }
+        if self.sysex.extend_from_slice(chunk).is_err() {
+            self.sysex.clear();
+            return Err(Error::Sysex(SysexError::Malformed));
+        }
+        if chunk.last() == Some(&SYSEX_END) {
+            return Ok(Some(&self.sysex));
+        }
+        Ok(None)
+    }
+}
+
+// assemble received bytes into lines, a line ends with a carriage return or
+// a line feed
+#[derive(Clone, Debug, Default)]
+pub struct LineBuffer {
+    line: Line,
+    overflow: bool,
+}
+
+impl LineBuffer {
+    // feed a received byte, return the line once complete
+    pub fn push(&mut self, byte: u8) -> Option<Result<Line, Error>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = core::mem::take(&mut self.line);
+                if core::mem::take(&mut self.overflow) {
+                    return Some(Err(Error::LineTooLong));
+                }
+                if line.trim().is_empty() {
+                    return None;
+                }
+                Some(Ok(line))
+            }
+            // backspace and delete
+            0x08 | 0x7F => {
+                self.line.pop();
+                None
+            }
+            0x20..=0x7E => {
+                if self.line.push(byte as char).is_err() {
+                    self.overflow = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+// replies waiting to be sent, text which does not fit is dropped
+#[derive(Clone, Debug)]
+pub struct Output {
+    buffer: Deque<u8, OUTPUT_SIZE>,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output {
+            buffer: Deque::new(),
+        }
+    }
+
+    pub fn peek(&self) -> Option<u8> {
+        self.buffer.front().copied()
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        self.buffer.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // drop the bytes queued past a length
+    pub fn truncate(&mut self, len: usize) {
+        while self.buffer.len() > len {
+            self.buffer.pop_back();
+        }
+    }
+
+    // queue binary data, nothing is queued if it does not fit
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> bool {
+        if self.buffer.capacity() - self.buffer.len() < bytes.len() {
+            return false;
+        }
+        for byte in bytes {
+            self.buffer.push_back(*byte).ok();
+        }
+        true
+    }
+}
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // terminals expect carriage returns before line feeds
+            if byte == b'\n' {
+                self.buffer.push_back(b'\r').map_err(|_| fmt::Error)?;
+            }
+            self.buffer.push_back(byte).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notation::ErrorKind;
+    use crate::track::{Gate, Note};
+    use core::fmt::Write;
+    use std::string::String as StdString;
+
+    struct Sequencer {
+        tracks: [Track; TRACKS_COUNT],
+        current_track: usize,
+        settings: Settings,
+        loader: DumpLoader,
+    }
+
+    impl Sequencer {
+        fn new() -> Sequencer {
+            Sequencer {
+                tracks: [Track::new(); TRACKS_COUNT],
+                current_track: 0,
+                settings: Settings::new(),
+                loader: DumpLoader::default(),
+            }
+        }
+
+        // execute a command line and return the queued reply
+        fn run(&mut self, line: &str) -> StdString {
+            let mut out = Output::new();
+            self.run_on(line, &mut out);
+            sent(&mut out).replace("\r\n", "\n")
+        }
+
+        fn run_on(&mut self, line: &str, out: &mut Output) {
+            execute_queued(
+                line,
+                &mut self.tracks,
+                &mut self.current_track,
+                &mut self.settings,
+                &mut self.loader,
+                out,
+            );
+        }
+    }
+
+    fn sent(out: &mut Output) -> StdString {
+        let mut sent = StdString::new();
+        while let Some(byte) = out.pop() {
+            sent.push(byte as char);
+        }
+        sent
+    }
+
+    fn lines(bytes: &[u8]) -> std::vec::Vec<Result<Line, Error>> {
+        let mut buffer = LineBuffer::default();
+        bytes.iter().filter_map(|byte| buffer.push(*byte)).collect()
+    }
+
+    #[test]
+    fn settings_commands() {
+        assert_eq!(parse("tempo 128"), Ok(Command::Tempo(128)));
+        assert_eq!(parse("tempo 20"), Err(Error::InvalidArgument));
+        assert_eq!(parse("tempo fast"), Err(Error::InvalidArgument));
+        assert_eq!(parse("tempo"), Err(Error::MissingArgument));
+        assert_eq!(parse("gate 100"), Ok(Command::Gate(100)));
+        assert_eq!(parse("gate 0"), Err(Error::InvalidArgument));
+        assert_eq!(parse("swing 50"), Ok(Command::Swing(50)));
+        assert_eq!(parse("fill on"), Ok(Command::Fill(true)));
+        assert_eq!(parse("fill maybe"), Err(Error::InvalidArgument));
+        assert_eq!(parse("play now"), Err(Error::TooManyArguments));
+        assert_eq!(parse("jump"), Err(Error::UnknownCommand));
+        assert_eq!(parse("   "), Err(Error::MissingArgument));
+    }
+
+    #[test]
+    fn track_commands() {
+        assert_eq!(parse("track 3"), Ok(Command::Track(2, None)));
+        assert_eq!(parse("track 0"), Err(Error::InvalidArgument));
+        assert_eq!(parse("track 9"), Err(Error::InvalidArgument));
+        assert_eq!(
+            parse("  track 3 length 5 "),
+            Ok(Command::Track(2, Some(TrackSetting::Length(5))))
+        );
+        assert_eq!(
+            parse("track 1 transpose -24"),
+            Ok(Command::Track(0, Some(TrackSetting::Transpose(-24))))
+        );
+        assert_eq!(
+            parse("track 1 mode cv"),
+            Ok(Command::Track(0, Some(TrackSetting::Mode(TrackMode::CV))))
+        );
+        assert_eq!(
+            parse("track 8 glide 2000"),
+            Ok(Command::Track(7, Some(TrackSetting::Glide(2000))))
+        );
+        assert_eq!(
+            parse("track 2 swing off"),
+            Ok(Command::Track(1, Some(TrackSetting::Swing(None))))
+        );
+        assert_eq!(
+            parse("track 2 swing 25"),
+            Ok(Command::Track(1, Some(TrackSetting::Swing(Some(25)))))
+        );
+        assert_eq!(parse("track 1 glide 2001"), Err(Error::InvalidArgument));
+        assert_eq!(parse("track 1 mode"), Err(Error::MissingArgument));
+        assert_eq!(parse("track 1 speed 2"), Err(Error::InvalidArgument));
+        assert_eq!(parse("randomize 2 50"), Ok(Command::Randomize(1, 50)));
+        assert_eq!(parse("randomize 2 150"), Err(Error::InvalidArgument));
+    }
+
+    #[test]
+    fn pattern_commands() {
+        assert_eq!(parse("pattern 2"), Ok(Command::Pattern(1, None)));
+        match parse("pattern 2 \"C4 . E4 .\"") {
+            Ok(Command::Pattern(1, Some(pattern))) => {
+                assert_eq!(pattern[0].gate, Gate::ON);
+                assert_eq!(pattern[1].gate, Gate::OFF);
+                assert_eq!(pattern[2].note, Note::E);
+            }
+            command => panic!("{:?}", command),
+        }
+        // notation errors are reported with their position in the line
+        assert_eq!(
+            parse("pattern 2 \"C4 . H4 .\""),
+            Err(Error::Pattern(ParseError {
+                position: 16,
+                kind: ErrorKind::UnexpectedChar('H')
+            }))
+        );
+        assert_eq!(parse("pattern 2 \"C4"), Err(Error::InvalidArgument));
+        assert_eq!(parse("pattern 2 3 \"C4\""), Err(Error::TooManyArguments));
+        assert_eq!(parse("pattern 2 \"C4\" x"), Err(Error::TooManyArguments));
+    }
+
+    #[test]
+    fn step_commands() {
+        let mut expected = notation::parse("Bb3~").unwrap()[0];
+        expected.gate_length = 25;
+        expected.ratchets = 3;
+        expected.probability = 0;
+        expected.condition = Condition::cycle(2, 4).unwrap();
+        expected.slide = true;
+        expected.velocity = 64;
+        expected.nudge = -50;
+        assert_eq!(
+            parse("step 1 3 Bb3~ gate 25 ratchet 3 prob 0 cond 2:4 slide vel 64 nudge -50"),
+            Ok(Command::Step(0, 2, expected))
+        );
+        match parse("step 2 8 x cond !fill") {
+            Ok(Command::Step(1, 7, step)) => assert_eq!(step.condition, Condition::NotFill),
+            command => panic!("{:?}", command),
+        }
+        assert_eq!(
+            parse("step 1 3 Q"),
+            Err(Error::Pattern(ParseError {
+                position: 9,
+                kind: ErrorKind::UnexpectedChar('Q')
+            }))
+        );
+        assert_eq!(parse("step 1 9 C4"), Err(Error::InvalidArgument));
+        assert_eq!(parse("step 1 1 C4 ratchet 9"), Err(Error::InvalidArgument));
+        assert_eq!(parse("step 1 1 C4 cond 4:2"), Err(Error::InvalidArgument));
+        assert_eq!(parse("step 1 1 C4 cond"), Err(Error::MissingArgument));
+        assert_eq!(parse("step 1 1 C4 legato"), Err(Error::InvalidArgument));
+    }
+
+    #[test]
+    fn dump_commands() {
+        assert_eq!(parse("dump"), Ok(Command::Dump(None)));
+        assert_eq!(parse("dump 2"), Ok(Command::Dump(Some(1))));
+        match parse("load F0 7d 53 4 01 00 F7") {
+            Ok(Command::Load(sysex)) => {
+                assert_eq!(sysex[..], [0xF0, 0x7D, 0x53, 0x04, 0x01, 0x00, 0xF7])
+            }
+            command => panic!("{:?}", command),
+        }
+        assert_eq!(parse("load F0 XX"), Err(Error::InvalidArgument));
+        assert_eq!(parse("load F0 100"), Err(Error::InvalidArgument));
+        assert_eq!(parse("load"), Err(Error::MissingArgument));
+    }
+
+    #[test]
+    fn run_commands() {
+        let mut sequencer = Sequencer::new();
+        assert_eq!(sequencer.run("tempo 140"), "ok\n");
+        assert_eq!(sequencer.settings.get_bpm(), 140);
+        assert_eq!(sequencer.run("track 2"), "ok\n");
+        assert_eq!(sequencer.current_track, 1);
+        assert_eq!(sequencer.run("track 2 mode cv"), "ok\n");
+        assert_eq!(sequencer.run("track 2 length 4"), "ok\n");
+        assert_eq!(sequencer.run("track 2 swing 10"), "ok\n");
+        assert_eq!(sequencer.tracks[1].get_swing(), Some(10));
+        assert_eq!(sequencer.run("pattern 2 \"C4 . E4~ -\""), "ok\n");
+        assert_eq!(sequencer.run("step 2 2 G3"), "ok\n");
+        assert_eq!(sequencer.run("pattern 2"), "pattern 2 \"C4 G3 E4 -\"\nok\n");
+        // gate tracks are printed with gates only
+        assert_eq!(sequencer.run("pattern 1 \"C4 . E4\""), "ok\n");
+        assert_eq!(
+            sequencer.run("pattern 1"),
+            "pattern 1 \"x . x . . . . .\"\nok\n"
+        );
+        assert_eq!(sequencer.run("play"), "ok\n");
+        assert!(sequencer.tracks.iter_mut().all(|track| track.is_playing()));
+
+        let status = sequencer.run("status");
+        assert!(status.starts_with("tempo 140, gate "));
+        assert!(
+            status.contains("track 2: playing, cv, length 4, transpose 0, glide 0ms, swing 10%\n")
+        );
+
+        assert_eq!(sequencer.run("tempo 2"), "error: invalid argument\n");
+        assert_eq!(
+            sequencer.run("pattern 1 \"C4 H4\""),
+            "error: unexpected 'H' at position 14\n"
+        );
+        assert_eq!(
+            sequencer.run("load F0 7D 53 04 02 00 F7"),
+            "error: invalid argument\n"
+        );
+    }
+
+    #[test]
+    fn dump_and_load() {
+        let mut sequencer = Sequencer::new();
+        sequencer.run("track 3 transpose 5");
+        sequencer.run("pattern 3 \"C4 D4 . x\"");
+        sequencer.run("track 8 length 3");
+
+        for command in ["dump 3", "dump"] {
+            let dump = sequencer.run(command);
+            let dump = dump.strip_suffix("ok\n").unwrap();
+            assert!(dump.starts_with("load F0 7D 53 "));
+            assert!(dump.lines().count() > 1);
+
+            let mut restored = Sequencer::new();
+            for line in dump.lines() {
+                assert!(line.len() <= LINE_SIZE);
+                assert_eq!(restored.run(line), "ok\n");
+            }
+            assert_eq!(restored.tracks[2].get_transpose(), 5);
+            assert_eq!(
+                restored.tracks[2].get_pattern(),
+                sequencer.tracks[2].get_pattern()
+            );
+        }
+        assert_eq!(
+            sequencer.run("dump").lines().count(),
+            SYSEX_MAX_SIZE / 32 + 2
+        );
+
+        assert!(sequencer
+            .run("load F0 7D 53 01 02 00 F7")
+            .starts_with("error: invalid dump"));
+    }
+
+    #[test]
+    fn dump_overflow() {
+        let mut sequencer = Sequencer::new();
+        let mut out = Output::new();
+        // the dump fits behind a pending reply
+        sequencer.run_on("pattern 1", &mut out);
+        sequencer.run_on("dump", &mut out);
+        let reply = sent(&mut out);
+        assert!(reply.starts_with("pattern 1 \"") && reply.ends_with("F7\r\nok\r\n"));
+
+        // the partial dump is replaced by an error
+        assert!(out.write_bytes(&[0; OUTPUT_SIZE - 200]));
+        sequencer.run_on("dump", &mut out);
+        let reply = sent(&mut out);
+        assert_eq!(
+            reply.len(),
+            OUTPUT_SIZE - 200 + "error: output full\r\n".len()
+        );
+        assert!(reply.ends_with("\0error: output full\r\n"));
+    }
+
+    #[test]
+    fn dump_chunks() {
+        let mut loader = DumpLoader::default();
+        assert_eq!(
+            loader.push(&[0x01, 0x02]),
+            Err(Error::Sysex(SysexError::Malformed))
+        );
+        assert_eq!(loader.push(&[0xF0, 0x7D]), Ok(None));
+        assert_eq!(loader.push(&[0x53]), Ok(None));
+        // a new dump replaces the incomplete one
+        assert_eq!(loader.push(&[0xF0, 0x7E]), Ok(None));
+        assert_eq!(
+            loader.push(&[0x53, 0xF7]),
+            Ok(Some(&[0xF0, 0x7E, 0x53, 0xF7][..]))
+        );
+        assert_eq!(
+            loader.push(&[0x53, 0xF7]),
+            Err(Error::Sysex(SysexError::Malformed))
+        );
+
+        // dumps longer than the largest SysEx message
+        assert_eq!(loader.push(&[0xF0]), Ok(None));
+        let chunk = [0; CHUNK_SIZE];
+        for _ in 0..SYSEX_MAX_SIZE / CHUNK_SIZE {
+            assert_eq!(loader.push(&chunk), Ok(None));
+        }
+        assert_eq!(
+            loader.push(&chunk),
+            Err(Error::Sysex(SysexError::Malformed))
+        );
+        assert_eq!(
+            loader.push(&[0x00]),
+            Err(Error::Sysex(SysexError::Malformed))
+        );
+    }
+
+    #[test]
+    fn line_buffer() {
+        let received = lines(b"tempX\x08o 1\r\n\nplay\r");
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].as_ref().unwrap().as_str(), "tempo 1");
+        assert_eq!(received[1].as_ref().unwrap().as_str(), "play");
+
+        let mut long = std::vec![b'a'; LINE_SIZE + 1];
+        long.extend_from_slice(b"\nstop\n");
+        let received = lines(&long);
+        assert_eq!(received[0], Err(Error::LineTooLong));
+        assert_eq!(received[1].as_ref().unwrap().as_str(), "stop");
+    }
+
+    #[test]
+    fn output() {
+        let mut out = Output::new();
+        writeln!(out, "ok").unwrap();
+        assert!(out.write_bytes(&[0x00, 0x01]));
+        assert_eq!(out.peek(), Some(b'o'));
+        let mut sent = std::vec::Vec::new();
+        while let Some(byte) = out.pop() {
+            sent.push(byte);
+        }
+        assert_eq!(sent, b"ok\r\n\x00\x01");
+        assert!(!out.write_bytes(&[0; OUTPUT_SIZE + 1]));
+        assert!(out.is_empty());
+    }
+}
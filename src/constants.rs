@@ -3,16 +3,56 @@ use smart_leds::RGB;
 // sequencer
 pub const STEPS_COUNT: usize = 8;
 pub const TRACKS_COUNT: usize = 8;
-pub const BPM: f64 = 120.0;
-pub const GATE_LENGTH: f64 = 0.5;
+pub const BPM: u16 = 120;
+pub const MIN_BPM: u16 = 40;
+pub const MAX_BPM: u16 = 240;
+pub const GATE_LENGTH: u8 = 50; // percentage of the step length
+pub const MAX_TRANSPOSE: i8 = 24;
+pub const MAX_RATCHETS: u8 = 8; // gates played during a step
+pub const MAX_CONDITION_CYCLES: u8 = 8; // longest cycle of a trig condition
+pub const MAX_GLIDE_MS: u16 = 2000; // glide time of the sliding steps
+pub const SWING: u8 = 0; // delay of the even steps, percentage of the step length
+pub const MAX_SWING: u8 = 50;
+pub const MAX_NUDGE: u8 = 50; // offset of a step from the grid, percentage of a step
+
+// DAC outputs, 12 bits referenced to 5V
+pub const DAC_COUNT: usize = 2; // outputs of the first tracks
+pub const DAC_MAX: u16 = 4095;
+pub const CV_COUNTS_PER_OCTAVE: u32 = 819; // 1V/oct
+pub const CV_ZERO_PITCH: u8 = 36; // pitch at 0V, two octaves below middle C
+pub const OUTPUT_RATE_HZ: u32 = 1000; // output interrupt, firing events and gliding
 
 // keyboard
-pub const KEYBOARD_KEY_PRESS_DELAY_MS: u64 = 250;
-pub const KEYBOARD_REFRESH_MS: u64 = 50;
+pub const KEYBOARD_ROWS: usize = 6;
+pub const KEYBOARD_COLUMNS: usize = 3;
+pub const KEYBOARD_REFRESH_MS: u64 = 5;
+// a key has to stay in the same state this long to be considered changed
+pub const KEYBOARD_DEBOUNCE_MS: u64 = 20;
+pub const KEYBOARD_HOLD_MS: u64 = 300;
+pub const KEYBOARD_LONG_PRESS_MS: u64 = 1000;
+// maximum delay between two presses of a double tap
+pub const KEYBOARD_DOUBLE_TAP_MS: u64 = 300;
+// range of the note entry octave, 0 being the octave of middle C
+pub const KEYBOARD_MIN_OCTAVE: i8 = -4;
+pub const KEYBOARD_MAX_OCTAVE: i8 = 4;
+
+// midi
+pub const MIDI_CLOCKS_PER_STEP: u8 = 24;
+pub const USB_VID: u16 = 0x16c0;
+pub const USB_PID: u16 = 0x05e4;
+pub const MIDI_BAUD_RATE: u32 = 31_250;
+
+// console
+pub const CONSOLE_BAUD_RATE: u32 = 115_200;
+pub const RTT_POLL_MS: u64 = 50;
+pub const STORAGE_RETRY_MS: u64 = 500; // flash is written once the transport stops
 
 // leds
 pub const LED_COUNT: usize = 18;
 pub const LED_REFRESH_MS: u64 = 100;
+// WS2812 frame sent over SPI, 4 bytes per color and the reset latching them
+pub const LED_RESET_SIZE: usize = 140;
+pub const LED_FRAME_SIZE: usize = LED_COUNT * 12 + LED_RESET_SIZE;
 
 pub const LED_GATE_COLOR: RGB<u8> = RGB {
     r: 0x00,
@@ -81,6 +121,11 @@ pub const LED_TRACK_CV_MODE_COLOR: RGB<u8> = RGB {
     r: 0x10,
     g: 0x10,
 };
+pub const LED_TRACK_VELOCITY_MODE_COLOR: RGB<u8> = RGB {
+    b: 0x10,
+    r: 0x10,
+    g: 0x00,
+};
 pub const LED_CLOCK_COLOR: RGB<u8> = RGB {
     b: 0x00,
     r: 0x00,
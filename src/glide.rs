@@ -0,0 +1,65 @@
+// pitch CV of a DAC output moving to its target value, either at once or
+// ramping linearly over a glide time
+#[derive(Copy, Clone, Debug)]
+pub struct Glide {
+    from: u16,
+    target: u16,
+    // time in milliseconds the ramp started at, and its length
+    start: u64,
+    duration: u64,
+    // the output has not reached the end of the ramp yet
+    active: bool,
+}
+
+impl Default for Glide {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Glide {
+    pub fn new() -> Glide {
+        Glide {
+            from: 0,
+            target: 0,
+            start: 0,
+            duration: 0,
+            active: false,
+        }
+    }
+
+    // move to a value, ramping from the current value over a duration in
+    // milliseconds, a duration of 0 jumps to the value
+    pub fn set_target(&mut self, target: u16, now: u64, duration: u64) -> &mut Self {
+        self.from = self.value_at(now);
+        self.target = target;
+        self.start = now;
+        self.duration = duration;
+        self.active = duration > 0;
+        self
+    }
+
+    // value of the output at a time in milliseconds
+    pub fn value_at(&self, now: u64) -> u16 {
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed >= self.duration {
+            return self.target;
+        }
+        let delta = (self.target as i64 - self.from as i64) * elapsed as i64 / self.duration as i64;
+        (self.from as i64 + delta) as u16
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    // value to write to the output while ramping, the target being returned
+    // once more when the ramp is over
+    pub fn update(&mut self, now: u64) -> Option<u16> {
+        if !self.active {
+            return None;
+        }
+        self.active = now.saturating_sub(self.start) < self.duration;
+        Some(self.value_at(now))
+    }
+}
@@ -0,0 +1,333 @@
+use heapless::Deque;
+
+use crate::constants::*;
+
+// number of keys of the matrix, a key is identified by its row and column
+// as row * KEYBOARD_COLUMNS + column
+pub const KEYS_COUNT: usize = KEYBOARD_ROWS * KEYBOARD_COLUMNS;
+// events waiting to be handled
+const EVENTS_SIZE: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Gesture {
+    Pressed,
+    Released,
+    // sent once when the key has been down for KEYBOARD_HOLD_MS
+    Held,
+    // sent once when the key has been down for KEYBOARD_LONG_PRESS_MS
+    LongPress,
+    // sent after Pressed when the previous press was less than
+    // KEYBOARD_DOUBLE_TAP_MS ago
+    DoubleTap,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct KeyState {
+    // last sampled state and when it changed
+    sampled: bool,
+    changed_at: u64,
+    // debounced state
+    down: bool,
+    pressed_at: u64,
+    held: bool,
+    long_press: bool,
+    // press which may start a double tap
+    last_press: Option<u64>,
+}
+
+// debounce sampled key states and turn their changes into events
+#[derive(Clone, Debug)]
+pub struct KeyTracker {
+    keys: [KeyState; KEYS_COUNT],
+    events: Deque<(usize, Gesture), EVENTS_SIZE>,
+}
+
+impl Default for KeyTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyTracker {
+    pub fn new() -> KeyTracker {
+        KeyTracker {
+            keys: [KeyState::default(); KEYS_COUNT],
+            events: Deque::new(),
+        }
+    }
+
+    // feed the state of every key sampled at a time in milliseconds, events
+    // which do not fit in the queue are dropped. Keys which may be ghosts keep
+    // their state
+    pub fn update(&mut self, now: u64, sample: &[bool; KEYS_COUNT]) {
+        let mut down = [false; KEYS_COUNT];
+        for (down, key) in down.iter_mut().zip(&self.keys) {
+            *down = key.down;
+        }
+        let ghosts = ghosts(sample, &down);
+        for (index, key) in self.keys.iter_mut().enumerate() {
+            let sampled = if ghosts[index] {
+                key.down
+            } else {
+                sample[index]
+            };
+            if sampled != key.sampled {
+                key.sampled = sampled;
+                key.changed_at = now;
+            }
+
+            if key.sampled != key.down && now.saturating_sub(key.changed_at) >= KEYBOARD_DEBOUNCE_MS
+            {
+                key.down = key.sampled;
+                if !key.down {
+                    self.events.push_back((index, Gesture::Released)).ok();
+                    continue;
+                }
+                self.events.push_back((index, Gesture::Pressed)).ok();
+                let double_tap = matches!(key.last_press,
+                    Some(last) if now - last <= KEYBOARD_DOUBLE_TAP_MS);
+                if double_tap {
+                    self.events.push_back((index, Gesture::DoubleTap)).ok();
+                }
+                // a third press starts a new double tap
+                key.last_press = if double_tap { None } else { Some(now) };
+                key.pressed_at = now;
+                key.held = false;
+                key.long_press = false;
+            } else if key.down {
+                let duration = now.saturating_sub(key.pressed_at);
+                if !key.held && duration >= KEYBOARD_HOLD_MS {
+                    key.held = true;
+                    self.events.push_back((index, Gesture::Held)).ok();
+                }
+                if !key.long_press && duration >= KEYBOARD_LONG_PRESS_MS {
+                    key.long_press = true;
+                    self.events.push_back((index, Gesture::LongPress)).ok();
+                }
+            }
+        }
+    }
+
+    // debounced state of a key
+    pub fn is_down(&self, index: usize) -> bool {
+        self.keys[index].down
+    }
+
+    pub fn next_event(&mut self) -> Option<(usize, Gesture)> {
+        self.events.pop_front()
+    }
+}
+
+// keys of the matrix which may be ghosts, given the sampled and debounced
+// states. Without diodes, pressing three corners of a rectangle of the matrix
+// makes the fourth one read as pressed, the four keys are then ambiguous. The
+// matrix also shows a phantom key at the corner sharing a row and a column
+// with two pressed keys (ie: Fn2 when pressing Fn1 and Forward). That corner
+// is only a phantom if it was not already down, keys which are held are real
+pub fn ghosts(sample: &[bool; KEYS_COUNT], down: &[bool; KEYS_COUNT]) -> [bool; KEYS_COUNT] {
+    let index = |row: usize, column: usize| row * KEYBOARD_COLUMNS + column;
+    let mut ghosts = [false; KEYS_COUNT];
+    for top in 0..KEYBOARD_ROWS {
+        for bottom in top + 1..KEYBOARD_ROWS {
+            for left in 0..KEYBOARD_COLUMNS {
+                for right in left + 1..KEYBOARD_COLUMNS {
+                    let corners = [
+                        index(top, left),
+                        index(top, right),
+                        index(bottom, right),
+                        index(bottom, left),
+                    ];
+                    let pressed = corners.iter().filter(|corner| sample[**corner]).count();
+                    match pressed {
+                        4 => corners.iter().for_each(|corner| ghosts[*corner] = true),
+                        3 => {
+                            let released = corners.iter().position(|corner| !sample[*corner]);
+                            let phantom = corners[(released.unwrap() + 2) % 4];
+                            ghosts[phantom] |= !down[phantom];
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    ghosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FN1: (usize, usize) = (0, 0);
+    const FN2: (usize, usize) = (5, 0);
+    const FORWARD: (usize, usize) = (5, 2);
+
+    fn index(key: (usize, usize)) -> usize {
+        key.0 * KEYBOARD_COLUMNS + key.1
+    }
+
+    // state of the matrix with the given keys pressed
+    fn sample(keys: &[(usize, usize)]) -> [bool; KEYS_COUNT] {
+        let mut sample = [false; KEYS_COUNT];
+        for key in keys {
+            sample[index(*key)] = true;
+        }
+        sample
+    }
+
+    fn marked(ghosts: &[bool; KEYS_COUNT]) -> std::vec::Vec<usize> {
+        (0..KEYS_COUNT).filter(|i| ghosts[*i]).collect()
+    }
+
+    // sample the matrix every 5ms from start until end, return the events
+    fn scan(
+        tracker: &mut KeyTracker,
+        start: u64,
+        end: u64,
+        keys: &[(usize, usize)],
+    ) -> std::vec::Vec<(usize, Gesture)> {
+        for now in (start..end).step_by(5) {
+            tracker.update(now, &sample(keys));
+        }
+        let mut events = std::vec::Vec::new();
+        while let Some(event) = tracker.next_event() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn gestures() {
+        let mut tracker = KeyTracker::new();
+        let key = index((2, 1));
+        // bounces shorter than the debounce time are ignored
+        assert_eq!(scan(&mut tracker, 0, 10, &[(2, 1)]), []);
+        assert_eq!(scan(&mut tracker, 10, 100, &[]), []);
+        assert_eq!(
+            scan(&mut tracker, 100, 100 + KEYBOARD_DEBOUNCE_MS + 5, &[(2, 1)]),
+            [(key, Gesture::Pressed)]
+        );
+        assert!(tracker.is_down(key));
+        assert_eq!(
+            scan(&mut tracker, 125, 1200, &[(2, 1)]),
+            [(key, Gesture::Held), (key, Gesture::LongPress)]
+        );
+        assert_eq!(
+            scan(&mut tracker, 1200, 1300, &[]),
+            [(key, Gesture::Released)]
+        );
+        assert!(!tracker.is_down(key));
+
+        // two presses within the double tap time, then a third one
+        assert_eq!(
+            scan(&mut tracker, 2000, 2050, &[(2, 1)]),
+            [(key, Gesture::Pressed)]
+        );
+        scan(&mut tracker, 2050, 2100, &[]);
+        assert_eq!(
+            scan(&mut tracker, 2100, 2150, &[(2, 1)]),
+            [(key, Gesture::Pressed), (key, Gesture::DoubleTap)]
+        );
+        scan(&mut tracker, 2150, 2200, &[]);
+        assert_eq!(
+            scan(&mut tracker, 2200, 2250, &[(2, 1)]),
+            [(key, Gesture::Pressed)]
+        );
+    }
+
+    #[test]
+    fn ghost_rectangles() {
+        let up = [false; KEYS_COUNT];
+        assert_eq!(marked(&ghosts(&sample(&[FN1, FORWARD]), &up)), []);
+        // keys of the same row or column are not ambiguous
+        assert_eq!(marked(&ghosts(&sample(&[FN1, FN2, (3, 0)]), &up)), []);
+        // phantom at the corner of two pressed keys
+        assert_eq!(
+            marked(&ghosts(&sample(&[FN1, FORWARD, FN2]), &up)),
+            [index(FN2)]
+        );
+        // a held key is never a phantom
+        assert_eq!(
+            marked(&ghosts(&sample(&[FN1, FORWARD, FN2]), &sample(&[FN2]))),
+            []
+        );
+        // four pressed corners are all ambiguous
+        let rectangle = [(1, 1), (1, 2), (3, 1), (3, 2)];
+        assert_eq!(
+            marked(&ghosts(&sample(&rectangle), &up)),
+            rectangle.map(index)
+        );
+        // phantoms of two rectangles
+        assert_eq!(
+            marked(&ghosts(&sample(&[(0, 1), (2, 0), (2, 1), (4, 0)]), &up)),
+            [index((2, 0)), index((2, 1))]
+        );
+    }
+
+    #[test]
+    fn phantom_key() {
+        let mut tracker = KeyTracker::new();
+        assert_eq!(
+            scan(&mut tracker, 0, 100, &[FN1]),
+            [(index(FN1), Gesture::Pressed)]
+        );
+        // Fn2 reads as pressed with Fn1 and Forward
+        assert_eq!(
+            scan(&mut tracker, 100, 200, &[FN1, FORWARD, FN2]),
+            [(index(FORWARD), Gesture::Pressed)]
+        );
+        assert!(!tracker.is_down(index(FN2)));
+        assert_eq!(
+            scan(&mut tracker, 200, 300, &[FN1]),
+            [(index(FORWARD), Gesture::Released)]
+        );
+    }
+
+    #[test]
+    fn held_corner_key() {
+        let mut tracker = KeyTracker::new();
+        scan(&mut tracker, 0, 50, &[FN2]);
+        scan(&mut tracker, 50, 100, &[FN2, FN1]);
+        // Fn2 was held before the rectangle was complete, Forward is real
+        assert_eq!(
+            scan(&mut tracker, 100, 150, &[FN2, FN1, FORWARD]),
+            [(index(FORWARD), Gesture::Pressed)]
+        );
+        assert!(tracker.is_down(index(FN2)));
+        assert_eq!(
+            scan(&mut tracker, 150, 200, &[FN1]),
+            [
+                (index(FN2), Gesture::Released),
+                (index(FORWARD), Gesture::Released)
+            ]
+        );
+    }
+
+    #[test]
+    fn ambiguous_keys_keep_their_state() {
+        let mut tracker = KeyTracker::new();
+        let [top_left, top_right, bottom_right, bottom_left] = [(1, 1), (1, 2), (3, 2), (3, 1)];
+        scan(&mut tracker, 0, 50, &[top_left]);
+        scan(&mut tracker, 50, 100, &[top_left, top_right]);
+        assert_eq!(
+            scan(&mut tracker, 100, 150, &[top_left, top_right, bottom_left]),
+            [(index(bottom_left), Gesture::Pressed)]
+        );
+        // the fourth corner can not be told from a ghost
+        let rectangle = [top_left, top_right, bottom_right, bottom_left];
+        assert_eq!(scan(&mut tracker, 150, 200, &rectangle), []);
+        assert!(!tracker.is_down(index(bottom_right)));
+        // nor from a phantom of the two keys left
+        assert_eq!(
+            scan(&mut tracker, 200, 250, &rectangle[1..]),
+            [(index(top_left), Gesture::Released)]
+        );
+        assert_eq!(
+            scan(&mut tracker, 250, 300, &rectangle[2..]),
+            [
+                (index(top_right), Gesture::Released),
+                (index(bottom_right), Gesture::Pressed)
+            ]
+        );
+    }
+}
@@ -1,5 +1,4 @@
 use rtic::mutex_prelude::*;
-use rtt_target::rprintln;
 
 use core::convert::Infallible;
 use embedded_hal::adc::OneShot;
@@ -12,7 +11,10 @@ use keypad::embedded_hal::digital::v2::InputPin;
 use keypad::{keypad_new, keypad_struct, KeypadInput};
 
 use crate::constants::*;
-use crate::track::{Note, Track};
+use crate::key_events::{Gesture, KeyTracker, KEYS_COUNT};
+use crate::keymap::KEYMAP;
+use crate::pitch::Pitch;
+use crate::track::Track;
 
 // initialise keyboard
 keypad_struct! {
@@ -50,9 +52,94 @@ pub enum CodeKey {
     KEY9,
 }
 
+// code keys in step order
+const CODE_KEYS: [CodeKey; 13] = [
+    CodeKey::KEY0,
+    CodeKey::KEY1,
+    CodeKey::KEY2,
+    CodeKey::KEY3,
+    CodeKey::KEY4,
+    CodeKey::KEY5,
+    CodeKey::KEY6,
+    CodeKey::KEY7,
+    CodeKey::KEY8,
+    CodeKey::KEY9,
+    CodeKey::KEY10,
+    CodeKey::KEY11,
+    CodeKey::KEY12,
+];
+
+impl CodeKey {
+    // position of the key in step order
+    pub fn index(&self) -> usize {
+        CODE_KEYS.iter().position(|key| key == self).unwrap()
+    }
+}
+
+// set of code keys, as a bitmask indexed by step order
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CodeKeys(u16);
+
+impl CodeKeys {
+    pub fn new() -> CodeKeys {
+        CodeKeys(0)
+    }
+
+    pub fn insert(&mut self, key: CodeKey) -> &mut Self {
+        self.0 |= 1 << key.index();
+        self
+    }
+
+    pub fn remove(&mut self, key: CodeKey) -> &mut Self {
+        self.0 &= !(1 << key.index());
+        self
+    }
+
+    pub fn contains(&self, key: CodeKey) -> bool {
+        self.0 & (1 << key.index()) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    // keys in step order
+    pub fn iter(&self) -> impl Iterator<Item = CodeKey> {
+        let bits = self.0;
+        CODE_KEYS
+            .into_iter()
+            .enumerate()
+            .filter(move |(index, _)| bits & (1 << index) != 0)
+            .map(|(_, key)| key)
+    }
+}
+
 pub struct Keyboard {
     keypad: Keypad,
+    tracker: KeyTracker,
+    // octave of the notes entered
+    octave: i8,
     pub key_event: KeyEvent,
+    pub entry: NoteEntry,
+}
+
+// state of the note entry in CV mode
+#[derive(Debug, Default)]
+pub struct NoteEntry {
+    // key pressed alone, its note is recorded when released
+    pub pending: Option<CodeKey>,
+    // step key held to edit the note of the step
+    pub editing: Option<(CodeKey, usize)>,
+    // notes of the chord being pressed which are already recorded
+    pub chord_recorded: usize,
 }
 use stm32f1xx_hal::gpio::gpioa::Parts;
 
@@ -81,9 +168,25 @@ pub enum Key {
     ModifierKey(ModifierKey),
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyboardError {
+    // no key is mapped at a row and column of the matrix
+    UnknownKey(usize, usize),
+}
+
+// change of a key
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Event {
+    pub key: Key,
+    pub gesture: Gesture,
+}
+
+// keys currently down
 #[derive(Debug)]
 pub struct KeyEvent {
+    // last pressed code key still down
     pub code: Option<CodeKey>,
+    pub codes: CodeKeys,
     pub nav: Option<NavKey>,
     pub modifier: Option<ModifierKey>,
     pub function: Option<FunctionKey>,
@@ -102,8 +205,12 @@ impl Keyboard {
         c2: Pin<Output<OpenDrain>, CRH, 'A', 10>,
     ) -> Keyboard {
         Keyboard {
+            tracker: KeyTracker::new(),
+            octave: 0,
+            entry: NoteEntry::default(),
             key_event: KeyEvent {
                 code: None,
+                codes: CodeKeys::new(),
                 nav: None,
                 modifier: None,
                 function: None,
@@ -115,86 +222,75 @@ impl Keyboard {
         }
     }
 
-    // match key to note
-    pub fn match_note(&self, key: CodeKey) -> Option<Note> {
-        match key {
-            CodeKey::KEY0 => Some(Note::C),
-            CodeKey::KEY1 => Some(Note::D),
-            CodeKey::KEY2 => Some(Note::E),
-            CodeKey::KEY3 => Some(Note::F),
-            CodeKey::KEY4 => Some(Note::G),
-            CodeKey::KEY5 => Some(Note::A),
-            CodeKey::KEY6 => Some(Note::B),
-            CodeKey::KEY7 => Some(Note::C),
-            CodeKey::KEY8 => Some(Note::Db),
-            CodeKey::KEY9 => Some(Note::Eb),
-            CodeKey::KEY10 => Some(Note::Gb),
-            CodeKey::KEY11 => Some(Note::Ab),
-            CodeKey::KEY12 => Some(Note::Bb),
-        }
+    // match key to a pitch of the entry octave
+    pub fn match_pitch(&self, key: CodeKey) -> Option<Pitch> {
+        Some(KEYMAP.pitch(key, self.octave))
+    }
+
+    pub fn get_octave(&mut self) -> i8 {
+        self.octave
+    }
+
+    pub fn set_octave(&mut self, octave: i8) -> &mut Self {
+        self.octave = octave.clamp(KEYBOARD_MIN_OCTAVE, KEYBOARD_MAX_OCTAVE);
+        self
     }
 
     // match key to step index
     pub fn match_step(&self, key: CodeKey) -> Option<usize> {
-        match key {
-            CodeKey::KEY0 => Some(0),
-            CodeKey::KEY1 => Some(1),
-            CodeKey::KEY2 => Some(2),
-            CodeKey::KEY3 => Some(3),
-            CodeKey::KEY4 => Some(4),
-            CodeKey::KEY5 => Some(5),
-            CodeKey::KEY6 => Some(6),
-            CodeKey::KEY7 => Some(7),
-            CodeKey::KEY8 => Some(8),
-            CodeKey::KEY9 => Some(9),
-            CodeKey::KEY10 => Some(10),
-            CodeKey::KEY11 => Some(11),
-            CodeKey::KEY12 => Some(12),
-        }
+        Some(key.index())
     }
 
-    pub fn read(&mut self) {
-        self.key_event.code = None;
+    // key at a matrix position
+    fn key_at(row: usize, column: usize) -> Result<Key, KeyboardError> {
+        KEYMAP
+            .key_at(row, column)
+            .ok_or(KeyboardError::UnknownKey(row, column))
+    }
+
+    // sample the keys, now being the time in milliseconds. Key changes are
+    // queued as events and key_event holds the keys currently down
+    pub fn read(&mut self, now: u64) {
+        let mut sample = [false; KEYS_COUNT];
+        for (row_index, row) in self.keypad.decompose().iter().enumerate() {
+            for (col_index, k) in row.iter().enumerate() {
+                sample[row_index * KEYBOARD_COLUMNS + col_index] = k.is_low().unwrap();
+            }
+        }
+        self.tracker.update(now, &sample);
+
+        self.key_event.codes = CodeKeys::new();
         self.key_event.function = None;
         self.key_event.modifier = None;
         self.key_event.nav = None;
 
-        for (row_index, row) in self.keypad.decompose().iter().enumerate() {
-            for (col_index, k) in row.iter().enumerate() {
-                if k.is_low().unwrap() {
-                    rprintln!("Pressed: ({}, {})", row_index, col_index);
-
-                    match (row_index, col_index) {
-                        (0, 0) => self.key_event.function = Some(FunctionKey::FN1),
-                        (0, 1) => self.key_event.modifier = Some(ModifierKey::SHIFT),
-                        (0, 2) => self.key_event.code = Some(CodeKey::KEY0),
-                        (1, 0) => self.key_event.code = Some(CodeKey::KEY8),
-                        (1, 1) => self.key_event.code = Some(CodeKey::KEY1),
-                        (1, 2) => self.key_event.code = Some(CodeKey::KEY2),
-                        (2, 0) => self.key_event.code = Some(CodeKey::KEY9),
-                        (2, 1) => self.key_event.code = Some(CodeKey::KEY3),
-                        (2, 2) => self.key_event.code = Some(CodeKey::KEY4),
-                        (3, 0) => self.key_event.code = Some(CodeKey::KEY10),
-                        (3, 1) => self.key_event.code = Some(CodeKey::KEY5),
-                        (3, 2) => self.key_event.code = Some(CodeKey::KEY6),
-                        (4, 0) => self.key_event.code = Some(CodeKey::KEY11),
-                        (4, 1) => self.key_event.code = Some(CodeKey::KEY12),
-                        (4, 2) => self.key_event.code = Some(CodeKey::KEY7),
-                        (5, 0) => {
-                            // there is a bug there, for some reason when
-                            // pressing Fn1 and Forward key, Fn2 also appear...
-                            // so to prevent it to override, only set if
-                            // FN1 hasn't been pressed already
-                            if self.key_event.function == None {
-                                self.key_event.function = Some(FunctionKey::FN2)
-                            }
-                        }
-                        (5, 1) => self.key_event.nav = Some(NavKey::BACK),
-                        (5, 2) => self.key_event.nav = Some(NavKey::FORWARD),
-                        (_, _) => todo!(),
-                    }
+        for index in (0..KEYS_COUNT).filter(|index| self.tracker.is_down(*index)) {
+            match Self::key_at(index / KEYBOARD_COLUMNS, index % KEYBOARD_COLUMNS) {
+                Ok(Key::CodeKey(code)) => {
+                    self.key_event.codes.insert(code);
+                }
+                Ok(Key::ModifierKey(modifier)) => self.key_event.modifier = Some(modifier),
+                Ok(Key::NavKey(nav)) => self.key_event.nav = Some(nav),
+                Ok(Key::FunctionKey(function)) => self.key_event.function = Some(function),
+                Err(_) => {}
+            }
+        }
+        // keep the last pressed key, set when its event is handled
+        if !matches!(self.key_event.code, Some(code) if self.key_event.codes.contains(code)) {
+            self.key_event.code = self.key_event.codes.iter().next();
+        }
+    }
+
+    // next key event since the last reads
+    pub fn next_event(&mut self) -> Option<Event> {
+        while let Some((index, gesture)) = self.tracker.next_event() {
+            if let Ok(key) = Self::key_at(index / KEYBOARD_COLUMNS, index % KEYBOARD_COLUMNS) {
+                if let (Key::CodeKey(code), Gesture::Pressed) = (key, gesture) {
+                    self.key_event.code = Some(code);
                 }
+                return Some(Event { key, gesture });
             }
         }
+        None
     }
 }
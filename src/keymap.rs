@@ -0,0 +1,188 @@
+use crate::constants::*;
+use crate::key_events::Gesture;
+use crate::keyboard::{CodeKey, FunctionKey, Key, KeyEvent, ModifierKey, NavKey};
+use crate::pitch::Pitch;
+use crate::track::{Note, RecordMode};
+
+#[cfg(not(any(feature = "keymap-msk18")))]
+compile_error!("a keymap feature has to be enabled, ie: keymap-msk18");
+
+// actions triggered by key combos
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    ToggleTrackMode,
+    NextTrack,
+    PreviousTrack,
+    TogglePlay,
+    TogglePause,
+    ClearPattern,
+    // select the track of the code key
+    SelectTrack,
+    // randomize with a density given by the code key
+    Randomize,
+    // record a note or toggle a gate
+    StepInput,
+    DumpTrack,
+    DumpProject,
+    // learn a MIDI CC while held
+    LearnCc,
+    // change the note entry octave
+    OctaveUp,
+    OctaveDown,
+    // start or stop live recording in the given mode
+    Record(RecordMode),
+    // play the steps with a fill condition
+    ToggleFill,
+}
+
+// keys held for an action, code being any code key, and the gesture of the
+// last key
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Combo {
+    pub function: Option<FunctionKey>,
+    pub modifier: Option<ModifierKey>,
+    pub nav: Option<NavKey>,
+    pub code: bool,
+    pub gesture: Gesture,
+}
+
+const fn combo(
+    function: Option<FunctionKey>,
+    modifier: Option<ModifierKey>,
+    nav: Option<NavKey>,
+    code: bool,
+) -> Combo {
+    Combo {
+        function,
+        modifier,
+        nav,
+        code,
+        gesture: Gesture::Pressed,
+    }
+}
+
+const fn double_tap(combo: Combo) -> Combo {
+    Combo {
+        gesture: Gesture::DoubleTap,
+        ..combo
+    }
+}
+
+pub struct Keymap {
+    // key at each row and column of the matrix
+    pub keys: [[Option<Key>; KEYBOARD_COLUMNS]; KEYBOARD_ROWS],
+    // semitones from the C of the entry octave of each code key, in step order
+    pub notes: [u8; 13],
+    // code keys setting the velocity of a held step, from soft to loud
+    pub levels: &'static [CodeKey],
+    pub combos: &'static [(Combo, Action)],
+}
+
+impl Keymap {
+    pub fn key_at(&self, row: usize, column: usize) -> Option<Key> {
+        *self.keys.get(row)?.get(column)?
+    }
+
+    // pitch of a code key, octave 0 being the octave of middle C
+    pub fn pitch(&self, key: CodeKey, octave: i8) -> Pitch {
+        Pitch::from_note(Note::C, octave).transpose(self.notes[key.index()] as i16)
+    }
+
+    // velocity of a level key, the last level being the loudest
+    pub fn velocity(&self, key: CodeKey) -> Option<u8> {
+        let level = self.levels.iter().position(|level| *level == key)?;
+        Some(((level + 1) * 255 / self.levels.len()) as u8)
+    }
+
+    // action of the keys currently down when the last one made a gesture
+    pub fn action(&self, keys: &KeyEvent, gesture: Gesture) -> Option<Action> {
+        let held = Combo {
+            gesture,
+            ..combo(keys.function, keys.modifier, keys.nav, keys.code.is_some())
+        };
+        self.combos
+            .iter()
+            .find(|(combo, _)| *combo == held)
+            .map(|(_, action)| *action)
+    }
+}
+
+const FN1: Option<FunctionKey> = Some(FunctionKey::FN1);
+const FN2: Option<FunctionKey> = Some(FunctionKey::FN2);
+const SHIFT: Option<ModifierKey> = Some(ModifierKey::SHIFT);
+const BACK: Option<NavKey> = Some(NavKey::BACK);
+const FORWARD: Option<NavKey> = Some(NavKey::FORWARD);
+
+// MSK18 keyboard prototype, see the README for the key combos
+#[cfg(feature = "keymap-msk18")]
+pub const KEYMAP: Keymap = Keymap {
+    keys: [
+        [
+            Some(Key::FunctionKey(FunctionKey::FN1)),
+            Some(Key::ModifierKey(ModifierKey::SHIFT)),
+            Some(Key::CodeKey(CodeKey::KEY0)),
+        ],
+        [
+            Some(Key::CodeKey(CodeKey::KEY8)),
+            Some(Key::CodeKey(CodeKey::KEY1)),
+            Some(Key::CodeKey(CodeKey::KEY2)),
+        ],
+        [
+            Some(Key::CodeKey(CodeKey::KEY9)),
+            Some(Key::CodeKey(CodeKey::KEY3)),
+            Some(Key::CodeKey(CodeKey::KEY4)),
+        ],
+        [
+            Some(Key::CodeKey(CodeKey::KEY10)),
+            Some(Key::CodeKey(CodeKey::KEY5)),
+            Some(Key::CodeKey(CodeKey::KEY6)),
+        ],
+        [
+            Some(Key::CodeKey(CodeKey::KEY11)),
+            Some(Key::CodeKey(CodeKey::KEY12)),
+            Some(Key::CodeKey(CodeKey::KEY7)),
+        ],
+        [
+            Some(Key::FunctionKey(FunctionKey::FN2)),
+            Some(Key::NavKey(NavKey::BACK)),
+            Some(Key::NavKey(NavKey::FORWARD)),
+        ],
+    ],
+    // white keys from C to the upper C, then black keys
+    notes: [0, 2, 4, 5, 7, 9, 11, 12, 1, 3, 6, 8, 10],
+    levels: &[
+        CodeKey::KEY8,
+        CodeKey::KEY9,
+        CodeKey::KEY10,
+        CodeKey::KEY11,
+        CodeKey::KEY12,
+    ],
+    combos: &[
+        (combo(FN1, SHIFT, None, false), Action::ToggleTrackMode),
+        (combo(None, SHIFT, FORWARD, false), Action::NextTrack),
+        (combo(None, SHIFT, BACK, false), Action::PreviousTrack),
+        (combo(FN1, None, FORWARD, false), Action::TogglePlay),
+        (combo(FN1, None, BACK, false), Action::TogglePause),
+        (combo(FN2, SHIFT, None, false), Action::ClearPattern),
+        (combo(FN1, None, None, true), Action::SelectTrack),
+        (combo(FN2, None, None, true), Action::Randomize),
+        (combo(None, None, None, true), Action::StepInput),
+        (combo(FN2, None, BACK, false), Action::DumpTrack),
+        (combo(FN2, None, FORWARD, false), Action::DumpProject),
+        (combo(None, SHIFT, None, true), Action::LearnCc),
+        (combo(None, None, FORWARD, false), Action::OctaveUp),
+        (combo(None, None, BACK, false), Action::OctaveDown),
+        (
+            double_tap(combo(FN1, None, None, false)),
+            Action::Record(RecordMode::Replace),
+        ),
+        (
+            double_tap(combo(FN2, None, None, false)),
+            Action::Record(RecordMode::Overdub),
+        ),
+        (
+            double_tap(combo(None, SHIFT, None, false)),
+            Action::ToggleFill,
+        ),
+    ],
+};
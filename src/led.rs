@@ -1,23 +1,32 @@
 use crate::constants::*;
 use crate::keyboard::{CodeKey, FunctionKey, Key, ModifierKey, NavKey};
 use crate::track::{Note, TrackMode};
-use crate::ws2812::Ws2812;
-use smart_leds::{SmartLedsWrite, RGB};
+use smart_leds::RGB;
 use stm32f1xx_hal::{
+    dma::{dma1, Transfer, WriteDma, R},
     gpio::{Alternate, Pin, PullUp, PushPull, CRL},
-    spi::{NoMiso, NoSck, Spi, Spi1NoRemap},
+    spi::{NoMiso, NoSck, Spi, Spi1NoRemap, SpiTxDma},
 };
-use ws2812_spi as ws2812;
+
+// SPI bytes of 2 bits of a color at 3MHz, a WS2812 bit lasting 4 SPI bits
+const WS2812_PATTERNS: [u8; 4] = [0b1000_1000, 0b1000_1110, 0b1110_1000, 0b1110_1110];
+
+type LedSpiDma = SpiTxDma<
+    stm32f1xx_hal::pac::SPI1,
+    Spi1NoRemap,
+    (NoSck, NoMiso, Pin<Alternate<PushPull>, CRL, 'A', 7>),
+    dma1::C3,
+>;
+
+// the frame is sent with DMA, the SPI bus and the frame being given back once
+// the transfer is done
+enum LedBus {
+    Idle(&'static mut [u8], LedSpiDma),
+    Busy(Transfer<R, &'static mut [u8], LedSpiDma>),
+}
 
 pub struct LedDriver {
-    ws: Ws2812<
-        Spi<
-            stm32f1xx_hal::pac::SPI1,
-            Spi1NoRemap,
-            (NoSck, NoMiso, Pin<Alternate<PushPull>, CRL, 'A', 7>),
-            u8,
-        >,
-    >,
+    bus: Option<LedBus>,
     pub leds: [RGB<u8>; LED_COUNT],
 }
 
@@ -35,10 +44,12 @@ impl LedDriver {
             (NoSck, NoMiso, Pin<Alternate<PushPull>, CRL, 'A', 7>),
             u8,
         >,
+        channel: dma1::C3,
+        frame: &'static mut [u8; LED_FRAME_SIZE],
     ) -> LedDriver {
         let mut led_driver = LedDriver {
             leds: [RGB::default(); LED_COUNT],
-            ws: Ws2812::new(spi),
+            bus: Some(LedBus::Idle(frame, spi.with_tx_dma(channel))),
         };
         led_driver.clear().write();
         led_driver
@@ -60,10 +71,10 @@ impl LedDriver {
         self
     }
 
-    // set active gate color
-    pub fn set_gate_on(&mut self, index: usize) -> &mut Self {
+    // set active gate color, dimmed by the velocity of the step
+    pub fn set_gate_on(&mut self, index: usize, velocity: u8) -> &mut Self {
         if let Some(led) = match_step_to_led(index) {
-            self.leds[led] = LED_GATE_COLOR;
+            self.leds[led] = dim_color(LED_GATE_COLOR, velocity);
         }
         self
     }
@@ -102,11 +113,11 @@ impl LedDriver {
     // set active track move under Shift key
     pub fn set_track_mode(&mut self, track_mode: TrackMode) -> &mut Self {
         if let Some(led) = match_key_to_led(Key::ModifierKey(ModifierKey::SHIFT)) {
-            if track_mode == TrackMode::CV {
-                self.leds[led] = LED_TRACK_CV_MODE_COLOR;
-            } else {
-                self.leds[led] = LED_TRACK_GATE_MODE_COLOR;
-            }
+            self.leds[led] = match track_mode {
+                TrackMode::GATE => LED_TRACK_GATE_MODE_COLOR,
+                TrackMode::CV => LED_TRACK_CV_MODE_COLOR,
+                TrackMode::VELOCITY => LED_TRACK_VELOCITY_MODE_COLOR,
+            };
         }
         self
     }
@@ -119,8 +130,55 @@ impl LedDriver {
         self
     }
 
+    // encode the colors into the WS2812 frame and send it with DMA, without
+    // waiting for the transfer. A frame is skipped if the previous one is still
+    // being sent
     pub fn write(&mut self) {
-        self.ws.write(self.leds.iter().cloned()).unwrap();
+        let bus = match self.bus.take() {
+            Some(LedBus::Busy(transfer)) if transfer.is_done() => {
+                let (frame, dma) = transfer.wait();
+                LedBus::Idle(frame, dma)
+            }
+            Some(bus) => bus,
+            None => return,
+        };
+        self.bus = Some(match bus {
+            LedBus::Idle(frame, dma) => {
+                encode_frame(&self.leds, frame);
+                LedBus::Busy(dma.write(frame))
+            }
+            busy => busy,
+        });
+    }
+}
+
+// encode colors into a WS2812 frame, in GRB order, followed by the reset
+// latching the colors
+fn encode_frame(leds: &[RGB<u8>; LED_COUNT], frame: &mut [u8]) {
+    let (data, reset) = frame.split_at_mut(LED_COUNT * 12);
+    for (led, bytes) in leds.iter().zip(data.chunks_exact_mut(12)) {
+        for (color, out) in [led.g, led.r, led.b].iter().zip(bytes.chunks_exact_mut(4)) {
+            for (i, byte) in out.iter_mut().enumerate() {
+                *byte = WS2812_PATTERNS[((color >> (6 - 2 * i)) & 0b11) as usize];
+            }
+        }
+    }
+    reset.fill(0);
+}
+
+// scale a color by a level from 0 to 255, lit channels staying visible
+fn dim_color(color: RGB<u8>, level: u8) -> RGB<u8> {
+    let dim = |channel: u8| {
+        if channel == 0 {
+            0
+        } else {
+            ((channel as u16 * level as u16 / 255) as u8).max(1)
+        }
+    };
+    RGB {
+        r: dim(color.r),
+        g: dim(color.g),
+        b: dim(color.b),
     }
 }
 
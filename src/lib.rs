@@ -0,0 +1,19 @@
+#![cfg_attr(not(test), no_std)]
+
+// sequencer logic independent of the hardware, shared with the firmware and
+// tested on the host:
+// cargo test --lib --target x86_64-unknown-linux-gnu
+
+pub mod cc_map;
+pub mod console;
+pub mod constants;
+pub mod glide;
+pub mod key_events;
+pub mod midi;
+pub mod notation;
+pub mod pitch;
+pub mod remote;
+pub mod scheduler;
+pub mod settings;
+pub mod sysex;
+pub mod track;
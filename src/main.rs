@@ -6,7 +6,7 @@
 use panic_rtt_target as _;
 
 use rtic::app;
-use rtt_target::{rprintln, rtt_init_print};
+use rtt_target::{rprintln, rtt_init, set_print_channel, DownChannel};
 
 use core::marker::PhantomData;
 use embedded_hal::spi::{Mode, Phase, Polarity};
@@ -19,40 +19,61 @@ use stm32f1xx_hal::{
     },
     pac,
     prelude::*,
+    serial::{Config, Event, Rx, Serial, Tx},
     spi::{NoMiso, NoSck, Spi, Spi1NoRemap, Spi2NoRemap},
+    timer::{CounterHz, Event as TimerEvent},
+    usb::{Peripheral, UsbBus, UsbBusType},
 };
+use usb_device::{bus::UsbBusAllocator, prelude::*};
 
 use keypad::{keypad_new, keypad_struct, KeypadInput};
 use mcp49xx::{Command, Mcp49xx, MODE_0};
-use ws2812_spi as ws2812;
 
 pub const SPI_MODE: Mode = Mode {
     phase: Phase::CaptureOnSecondTransition,
     polarity: Polarity::IdleHigh,
 };
 
-mod constants;
+use sequencer_core::{
+    cc_map, console, constants, glide, key_events, midi, notation, pitch, remote, scheduler,
+    settings, sysex, track,
+};
+
 mod keyboard;
+mod keymap;
 mod led;
 mod sequencer;
-mod track;
+mod storage;
+mod usb_midi;
 
-#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [SPI1])]
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [USART2, TIM3])]
 mod app {
     use super::*;
-    use crate::pac::SPI2;
+    use crate::pac::{SPI2, TIM2, USART1, USART3};
     use mcp49xx::marker::{Buffered, Resolution12Bit, SingleChannel};
     use systick_monotonic::*;
 
+    use cc_map::CcMap;
     use constants::*;
+    use settings::Settings;
+    use storage::Storage;
     use track::*;
 
     use keyboard::{Keyboard, Keypad};
     use led::LedDriver;
+    use midi::ClockIn;
+    use scheduler::Scheduler;
     use sequencer::*;
+    use usb_midi::MidiClass;
 
     #[shared]
     struct Shared {
+        cc_map: CcMap,
+        clock_in: ClockIn,
+        console_out: console::Output,
+        // dump loaded by the console and RTT load commands
+        dump_loader: console::DumpLoader,
+        remote_subscribed: bool,
         current_track: usize,
         dac1: Mcp49xx<
             Pin<Output<PushPull>, CRH, 'B', 12>,
@@ -86,7 +107,15 @@ mod app {
             SingleChannel,
             Buffered,
         >,
+        // values fired by the output interrupt, waiting to be written
+        dac_values: [Option<u16>; DAC_COUNT],
+        // instant of the last step played, live recording is quantized to it
+        last_step: fugit::TimerInstantU64<1000>,
         led_driver: LedDriver,
+        midi: MidiClass<'static, UsbBusType>,
+        // outputs of the steps prepared ahead of the grid
+        scheduler: Scheduler,
+        settings: Settings,
         spi_dac: Spi<
             SPI2,
             Spi2NoRemap,
@@ -97,22 +126,49 @@ mod app {
             ),
             u8,
         >,
+        storage: Storage,
         tracks: [Track; TRACKS_COUNT],
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        usb_parser: midi::Parser,
     }
 
     #[local]
     struct Local {
-        step_length: fugit::Duration<u64, 1, 100>,
-        gate_length: fugit::Duration<u64, 1, 100>,
+        console_line: console::LineBuffer,
+        console_rx: Rx<USART1>,
+        console_tx: Tx<USART1>,
+        remote_frame: remote::FrameDecoder,
+        rtt_down: DownChannel,
+        rtt_line: console::LineBuffer,
         keyboard: Keyboard,
+        midi_parser: midi::Parser,
+        midi_rx: Rx<USART3>,
+        output_timer: CounterHz<TIM2>,
     }
 
     #[monotonic(binds = SysTick, default = true)]
-    type MonoTimer = Systick<100>; // 100 Hz / 10 ms granularity
+    type MonoTimer = Systick<1000>; // 1 kHz / 1 ms granularity
 
-    #[init]
+    #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBusType>> = None, led_frame: [u8; LED_FRAME_SIZE] = [0; LED_FRAME_SIZE]])]
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
-        rtt_init_print!();
+        // logs and console replies are sent on the up channel, commands are
+        // received on the down channel
+        let channels = rtt_init! {
+            up: {
+                0: {
+                    size: 1024
+                    mode: NoBlockSkip
+                    name: "Terminal"
+                }
+            }
+            down: {
+                0: {
+                    size: 64
+                    name: "Terminal"
+                }
+            }
+        };
+        set_print_channel(channels.up.0);
         rprintln!("init");
 
         let mut flash = cx.device.FLASH.constrain();
@@ -121,9 +177,15 @@ mod app {
 
         let clocks = rcc
             .cfgr
+            .use_hse(8.MHz())
             .sysclk(72.MHz())
             .pclk1(24.MHz())
             .freeze(&mut flash.acr);
+        assert!(clocks.usbclk_valid());
+
+        // MIDI CC mapping is persisted in flash
+        let mut storage = Storage::new(flash);
+        let cc_map = storage.load_cc_map();
 
         let mut gpioa = cx.device.GPIOA.split();
         let mut gpiob = cx.device.GPIOB.split();
@@ -144,7 +206,9 @@ mod app {
             clocks,
         );
 
-        let led_driver = LedDriver::new(spi_led);
+        // the LED frame is sent with DMA, SPI1 TX being on channel 3
+        let dma1 = cx.device.DMA1.split();
+        let led_driver = LedDriver::new(spi_led, dma1.3, cx.local.led_frame);
 
         // DAC
         let pins_dac = (
@@ -167,25 +231,74 @@ mod app {
         dac1.send(&mut spi_dac, cmd).unwrap();
         dac2.send(&mut spi_dac, cmd).unwrap();
 
+        // USB MIDI, pull D+ low for a moment to force the host to
+        // re-enumerate the device after a reset
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low();
+        cortex_m::asm::delay(clocks.sysclk().raw() / 100);
+
+        let usb = Peripheral {
+            usb: cx.device.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+        let usb_bus: &'static UsbBusAllocator<UsbBusType> =
+            cx.local.usb_bus.insert(UsbBus::new(usb));
+
+        let midi = MidiClass::new(usb_bus);
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(USB_VID, USB_PID))
+            .manufacturer("etiennetremel")
+            .product("STM32 Sequencer")
+            .serial_number("SEQ")
+            .build();
+
+        // MIDI in
+        let mut serial_midi = Serial::usart3(
+            cx.device.USART3,
+            (
+                gpiob.pb10.into_alternate_push_pull(&mut gpiob.crh),
+                gpiob.pb11,
+            ),
+            &mut afio.mapr,
+            Config::default().baudrate(MIDI_BAUD_RATE.bps()),
+            clocks,
+        );
+        serial_midi.listen(Event::Rxne);
+        let (_, midi_rx) = serial_midi.split();
+
+        // serial console, USART1 is remapped as PA9 and PA10 drive the keypad
+        let mut serial_console = Serial::usart1(
+            cx.device.USART1,
+            (
+                gpiob.pb6.into_alternate_push_pull(&mut gpiob.crl),
+                gpiob.pb7,
+            ),
+            &mut afio.mapr,
+            Config::default().baudrate(CONSOLE_BAUD_RATE.bps()),
+            clocks,
+        );
+        serial_console.listen(Event::Rxne);
+        let (console_tx, console_rx) = serial_console.split();
+
         // systick
         let systick = cx.core.SYST;
         let mut mono = Systick::new(systick, 72_000_000);
 
+        // outputs are fired from the timer interrupt
+        let mut output_timer = cx.device.TIM2.counter_hz(&clocks);
+        output_timer.start(OUTPUT_RATE_HZ.Hz()).unwrap();
+        output_timer.listen(TimerEvent::Update);
+
         let current_track = 0;
         let tracks = [track::Track::new(); TRACKS_COUNT];
 
-        // define step length
-        let step_length_us = (60.0 / BPM) * 1000.0 * 1000.0;
-        // define gate length
-        let gate_length_us = step_length_us * GATE_LENGTH;
+        let settings = Settings::new();
         rprintln!(
-            "Step duration: {:?}us, gate on duration: {:?}us",
-            step_length_us,
-            gate_length_us
+            "Step duration: {:?}ms, gate on duration: {:?}ms",
+            settings.step_length_ms(),
+            settings.gate_length_ms()
         );
-
-        let step_length = systick_monotonic::ExtU64::micros(step_length_us as u64);
-        let gate_length = systick_monotonic::ExtU64::micros(gate_length_us as u64);
+        let step_length = systick_monotonic::ExtU64::millis(settings.step_length_ms());
 
         // setup keyboard using led matrix schema
         let keyboard = Keyboard::new(
@@ -204,39 +317,108 @@ mod app {
         tick::spawn_after(step_length, mono.now()).unwrap();
         keyboard_ctrl::spawn_after(systick_monotonic::ExtU64::millis(KEYBOARD_REFRESH_MS)).unwrap();
         led_ctrl::spawn_after(systick_monotonic::ExtU64::millis(LED_REFRESH_MS)).unwrap();
+        rtt_poll::spawn_after(systick_monotonic::ExtU64::millis(RTT_POLL_MS)).unwrap();
 
         (
             Shared {
+                cc_map,
+                clock_in: ClockIn::default(),
+                console_out: console::Output::new(),
+                dump_loader: console::DumpLoader::default(),
+                remote_subscribed: false,
                 current_track,
                 dac1,
                 dac2,
+                dac_values: [None; DAC_COUNT],
+                last_step: mono.now(),
                 led_driver,
+                midi,
+                scheduler: Scheduler::new(),
+                settings,
                 spi_dac,
+                storage,
                 tracks,
+                usb_dev,
+                usb_parser: midi::Parser::default(),
             },
             Local {
-                gate_length,
+                console_line: console::LineBuffer::default(),
+                console_rx,
+                console_tx,
+                remote_frame: remote::FrameDecoder::default(),
+                rtt_down: channels.down.0,
+                rtt_line: console::LineBuffer::default(),
                 keyboard,
-                step_length,
+                midi_parser: midi::Parser::default(),
+                midi_rx,
+                output_timer,
             },
             init::Monotonics(mono),
         )
     }
 
     extern "Rust" {
-        #[task(local = [keyboard], shared = [tracks, led_driver,  current_track])]
+        #[task(local = [keyboard], shared = [tracks, led_driver,  current_track, midi, cc_map, settings, last_step])]
         fn keyboard_ctrl(cx: keyboard_ctrl::Context);
 
-        #[task(priority = 1, local = [step_length, gate_length], shared = [tracks, current_track])]
-        fn tick(cx: tick::Context, instant: fugit::TimerInstantU64<100>);
+        #[task(priority = 1, shared = [clock_in, settings])]
+        fn tick(cx: tick::Context, instant: fugit::TimerInstantU64<1000>);
+
+        #[task(local = [held: [Option<u8>; TRACKS_COUNT] = [None; TRACKS_COUNT]], shared = [tracks, settings, scheduler, remote_subscribed, console_out, last_step])]
+        fn step(cx: step::Context, instant: fugit::TimerInstantU64<1000>);
+
+        #[task(binds = TIM2, priority = 3, local = [output_timer, glides: [glide::Glide; DAC_COUNT] = [glide::Glide::new(); DAC_COUNT]], shared = [scheduler, dac_values])]
+        fn output_ctrl(cx: output_ctrl::Context);
+
+        #[task(priority = 2, shared = [dac_values, dac1, dac2, spi_dac])]
+        fn dac_out(cx: dac_out::Context);
+
+        // notes of the events fired in a burst by the output interrupt
+        #[task(priority = 2, capacity = 32, shared = [midi])]
+        fn midi_out(cx: midi_out::Context, message: midi::Message);
+
+        #[task(shared = [midi])]
+        fn clock_out(
+            cx: clock_out::Context,
+            instant: fugit::TimerInstantU64<1000>,
+            step_length: fugit::Duration<u64, 1, 1000>,
+            pulse: u8,
+        );
+
+        #[task(capacity = 16, shared = [tracks, current_track, clock_in, cc_map, settings])]
+        fn midi_in(cx: midi_in::Context, message: midi::Message);
+
+        #[task(binds = USART3, priority = 2, local = [midi_rx, midi_parser])]
+        fn usart_midi(cx: usart_midi::Context);
+
+        #[task(shared = [cc_map, storage, clock_in, tracks])]
+        fn save_cc_map(cx: save_cc_map::Context);
+
+        #[task(shared = [tracks, midi])]
+        fn sysex_in(cx: sysex_in::Context, sysex: midi::SysexBuffer);
+
+        #[task(binds = USART1, priority = 2, local = [console_rx, console_tx, console_line, remote_frame], shared = [console_out])]
+        fn usart_console(cx: usart_console::Context);
+
+        #[task(shared = [tracks, current_track, settings, dump_loader, console_out])]
+        fn console_cmd(cx: console_cmd::Context, line: console::Line);
+
+        #[task(shared = [tracks, current_track, settings, remote_subscribed, console_out])]
+        fn remote_cmd(cx: remote_cmd::Context, payload: remote::Payload);
+
+        #[task(local = [rtt_down, rtt_line], shared = [tracks, current_track, settings, dump_loader])]
+        fn rtt_poll(cx: rtt_poll::Context);
+
+        #[task(binds = USB_HP_CAN_TX, priority = 2, shared = [usb_dev, midi, usb_parser])]
+        fn usb_tx(cx: usb_tx::Context);
+
+        #[task(binds = USB_LP_CAN_RX0, priority = 2, shared = [usb_dev, midi, usb_parser])]
+        fn usb_rx0(cx: usb_rx0::Context);
 
         #[task(shared = [tracks, led_driver, current_track])]
         fn led_ctrl(cx: led_ctrl::Context);
 
-        #[task(shared = [tracks, dac1, dac2, spi_dac])]
-        fn cv_ctrl(cx: cv_ctrl::Context);
-
-        #[task(shared = [tracks, dac1, dac2, spi_dac])]
-        fn gate_reset(cx: gate_reset::Context);
+        #[task(shared = [scheduler, settings])]
+        fn audition(cx: audition::Context, index: usize, pitch: pitch::Pitch);
     }
 }
@@ -0,0 +1,619 @@
+use heapless::{Deque, Vec};
+
+use crate::sysex::{SYSEX_END, SYSEX_MAX_SIZE, SYSEX_START};
+
+// MIDI messages used by the sequencer and their USB-MIDI event packet
+// representation (USB MIDI 1.0 specification, section 4)
+
+// status bytes
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const TIMING_CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+
+// USB-MIDI code index numbers
+const CIN_SYSEX: u8 = 0x04;
+const CIN_SYSEX_END_1: u8 = 0x05;
+const CIN_SYSEX_END_2: u8 = 0x06;
+const CIN_SYSEX_END_3: u8 = 0x07;
+const CIN_SINGLE_BYTE: u8 = 0x0F;
+const CIN_NOTE_OFF: u8 = 0x08;
+const CIN_NOTE_ON: u8 = 0x09;
+const CIN_CONTROL_CHANGE: u8 = 0x0B;
+
+pub type SysexBuffer = Vec<u8, SYSEX_MAX_SIZE>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+}
+
+impl Message {
+    // encode message into MIDI bytes, return the number of bytes used
+    pub fn encode(&self, buf: &mut [u8; 3]) -> usize {
+        match *self {
+            Message::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => {
+                *buf = [NOTE_OFF | (channel & 0x0F), note & 0x7F, velocity & 0x7F];
+                3
+            }
+            Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                *buf = [NOTE_ON | (channel & 0x0F), note & 0x7F, velocity & 0x7F];
+                3
+            }
+            Message::ControlChange {
+                channel,
+                control,
+                value,
+            } => {
+                *buf = [
+                    CONTROL_CHANGE | (channel & 0x0F),
+                    control & 0x7F,
+                    value & 0x7F,
+                ];
+                3
+            }
+            Message::TimingClock => single(buf, TIMING_CLOCK),
+            Message::Start => single(buf, START),
+            Message::Continue => single(buf, CONTINUE),
+            Message::Stop => single(buf, STOP),
+        }
+    }
+
+    // decode a complete MIDI message, unsupported messages are ignored
+    pub fn decode(bytes: &[u8]) -> Option<Message> {
+        let status = *bytes.first()?;
+        match status {
+            TIMING_CLOCK => return Some(Message::TimingClock),
+            START => return Some(Message::Start),
+            CONTINUE => return Some(Message::Continue),
+            STOP => return Some(Message::Stop),
+            _ => {}
+        }
+
+        if bytes.len() < 3 {
+            return None;
+        }
+        let channel = status & 0x0F;
+        let (data1, data2) = (bytes[1] & 0x7F, bytes[2] & 0x7F);
+        match status & 0xF0 {
+            NOTE_OFF => Some(Message::NoteOff {
+                channel,
+                note: data1,
+                velocity: data2,
+            }),
+            // note on with a velocity of 0 is a note off
+            NOTE_ON if data2 == 0 => Some(Message::NoteOff {
+                channel,
+                note: data1,
+                velocity: 0,
+            }),
+            NOTE_ON => Some(Message::NoteOn {
+                channel,
+                note: data1,
+                velocity: data2,
+            }),
+            CONTROL_CHANGE => Some(Message::ControlChange {
+                channel,
+                control: data1,
+                value: data2,
+            }),
+            _ => None,
+        }
+    }
+
+    // code index number of the USB-MIDI event packet carrying this message
+    fn code_index(&self) -> u8 {
+        match self {
+            Message::NoteOff { .. } => CIN_NOTE_OFF,
+            Message::NoteOn { .. } => CIN_NOTE_ON,
+            Message::ControlChange { .. } => CIN_CONTROL_CHANGE,
+            _ => CIN_SINGLE_BYTE,
+        }
+    }
+
+    // wrap message into a 4 bytes USB-MIDI event packet
+    pub fn to_usb_packet(&self, cable: u8) -> [u8; 4] {
+        let mut bytes = [0; 3];
+        self.encode(&mut bytes);
+        [
+            (cable << 4) | self.code_index(),
+            bytes[0],
+            bytes[1],
+            bytes[2],
+        ]
+    }
+}
+
+// MIDI bytes carried by a 4 bytes USB-MIDI event packet
+pub fn usb_packet_data(packet: &[u8]) -> &[u8] {
+    let len = match packet[0] & 0x0F {
+        CIN_SINGLE_BYTE | CIN_SYSEX_END_1 => 1,
+        CIN_SYSEX_END_2 | 0x02 | 0x0C | 0x0D => 2,
+        CIN_SYSEX | CIN_SYSEX_END_3 | 0x03 | 0x08..=0x0B | 0x0E => 3,
+        _ => 0,
+    };
+    &packet[1..1 + len]
+}
+
+// split a SysEx message into USB-MIDI event packets
+pub fn sysex_usb_packets(sysex: &[u8], cable: u8) -> impl Iterator<Item = [u8; 4]> + '_ {
+    let chunks = sysex.chunks(3);
+    let count = chunks.len();
+    chunks.enumerate().map(move |(i, chunk)| {
+        let cin = match (i + 1 == count, chunk.len()) {
+            (false, _) => CIN_SYSEX,
+            (true, 1) => CIN_SYSEX_END_1,
+            (true, 2) => CIN_SYSEX_END_2,
+            (true, _) => CIN_SYSEX_END_3,
+        };
+        let mut packet = [(cable << 4) | cin, 0, 0, 0];
+        packet[1..1 + chunk.len()].copy_from_slice(chunk);
+        packet
+    })
+}
+
+// USB-MIDI event packets waiting to be written to an endpoint, messages
+// being queued whole or not at all
+#[derive(Clone, Debug, Default)]
+pub struct PacketQueue<const N: usize> {
+    packets: Deque<[u8; 4], N>,
+}
+
+impl<const N: usize> PacketQueue<N> {
+    pub fn new() -> PacketQueue<N> {
+        PacketQueue {
+            packets: Deque::new(),
+        }
+    }
+
+    pub fn push_message(&mut self, message: Message, cable: u8) -> Result<(), Message> {
+        self.packets
+            .push_back(message.to_usb_packet(cable))
+            .map_err(|_| message)
+    }
+
+    // queue the packets of a SysEx message, return false if they do not fit
+    pub fn push_sysex(&mut self, sysex: &[u8], cable: u8) -> bool {
+        if N - self.packets.len() < (sysex.len() + 2) / 3 {
+            return false;
+        }
+        for packet in sysex_usb_packets(sysex, cable) {
+            self.packets.push_back(packet).ok();
+        }
+        true
+    }
+
+    // copy as many packets as fit in a buffer, return the number of bytes
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for packet in self.packets.iter().take(buf.len() / 4) {
+            buf[len..len + 4].copy_from_slice(packet);
+            len += 4;
+        }
+        len
+    }
+
+    // drop the packets of a number of bytes written
+    pub fn consume(&mut self, len: usize) {
+        for _ in 0..len / 4 {
+            self.packets.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+// data received from a MIDI input
+#[derive(Debug, PartialEq)]
+pub enum Received<'a> {
+    Message(Message),
+    Sysex(&'a [u8]),
+}
+
+// MIDI byte stream parser with running status and SysEx support
+#[derive(Clone, Debug, Default)]
+pub struct Parser {
+    status: u8,
+    data: [u8; 2],
+    len: usize,
+    sysex: SysexBuffer,
+    receiving_sysex: bool,
+}
+
+impl Parser {
+    // feed a byte, return a message once it is complete
+    pub fn push(&mut self, byte: u8) -> Option<Received<'_>> {
+        match byte {
+            // realtime messages can appear anywhere, even between data bytes
+            0xF8..=0xFF => Message::decode(&[byte]).map(Received::Message),
+            SYSEX_START => {
+                self.status = 0;
+                self.sysex.clear();
+                self.receiving_sysex = self.sysex.push(byte).is_ok();
+                None
+            }
+            SYSEX_END if self.receiving_sysex => {
+                self.receiving_sysex = false;
+                match self.sysex.push(byte) {
+                    Ok(()) => Some(Received::Sysex(&self.sysex)),
+                    Err(_) => None,
+                }
+            }
+            // system common messages cancel running status
+            0xF1..=0xF7 => {
+                self.status = 0;
+                self.len = 0;
+                self.receiving_sysex = false;
+                None
+            }
+            0x80..=0xEF => {
+                self.status = byte;
+                self.len = 0;
+                self.receiving_sysex = false;
+                None
+            }
+            _ => {
+                if self.receiving_sysex {
+                    // drop messages which do not fit in the buffer
+                    self.receiving_sysex = self.sysex.push(byte).is_ok();
+                    return None;
+                }
+                if self.status == 0 {
+                    return None;
+                }
+                self.data[self.len] = byte;
+                self.len += 1;
+                if self.len < data_length(self.status) {
+                    return None;
+                }
+                self.len = 0;
+                Message::decode(&[self.status, self.data[0], self.data[1]]).map(Received::Message)
+            }
+        }
+    }
+}
+
+// number of data bytes following a channel status byte
+fn data_length(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+fn single(buf: &mut [u8; 3], status: u8) -> usize {
+    *buf = [status, 0, 0];
+    1
+}
+
+// MIDI clock follower, count incoming clock pulses and report when a new
+// step should be prepared. The period of the clock is measured so that steps
+// are prepared ahead of the grid, like on the internal clock
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ClockIn {
+    pulses: u8,
+    running: bool,
+    // time of the last pulse and interval between the last two pulses, in
+    // milliseconds
+    last_pulse: Option<u64>,
+    period: Option<u64>,
+    // the step starting with the next pulse was already prepared
+    prepared: bool,
+}
+
+impl ClockIn {
+    pub fn start(&mut self) -> &mut Self {
+        self.pulses = 0;
+        self.running = true;
+        self.prepared = false;
+        self
+    }
+
+    pub fn resume(&mut self) -> &mut Self {
+        self.running = true;
+        self.prepared = false;
+        self
+    }
+
+    pub fn stop(&mut self) -> &mut Self {
+        self.running = false;
+        self
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    // register a clock pulse received at now, in milliseconds, and return
+    // the time on the grid of the step to prepare. The next step is returned
+    // at the last pulse before its grid minus lookahead, its grid being
+    // estimated from the clock period. A step starting on a pulse which was
+    // not predicted, such as the first one, is returned with the pulse
+    pub fn pulse(&mut self, now: u64, pulses_per_step: u8, lookahead: u64) -> Option<u64> {
+        // the clock is measured while stopped, as most hosts keep sending it
+        self.period = self.last_pulse.map(|last| now.saturating_sub(last));
+        self.last_pulse = Some(now);
+        if !self.running {
+            return None;
+        }
+
+        let pulse = self.pulses;
+        self.pulses = (pulse + 1) % pulses_per_step;
+        if pulse == 0 && !core::mem::take(&mut self.prepared) {
+            return Some(now);
+        }
+
+        // pulses left until the next step, the following pulse being too
+        // late to prepare it lookahead before its grid
+        let left = (pulses_per_step - pulse) as u64;
+        match self.period {
+            Some(period) if !self.prepared && (left - 1) * period < lookahead => {
+                self.prepared = true;
+                Some(now + left * period)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGES: [Message; 7] = [
+        Message::NoteOff {
+            channel: 3,
+            note: 60,
+            velocity: 64,
+        },
+        Message::NoteOn {
+            channel: 15,
+            note: 127,
+            velocity: 1,
+        },
+        Message::ControlChange {
+            channel: 0,
+            control: 74,
+            value: 127,
+        },
+        Message::TimingClock,
+        Message::Start,
+        Message::Continue,
+        Message::Stop,
+    ];
+
+    fn parse(parser: &mut Parser, bytes: &[u8]) -> std::vec::Vec<Message> {
+        bytes
+            .iter()
+            .filter_map(|byte| match parser.push(*byte) {
+                Some(Received::Message(message)) => Some(message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encode_decode() {
+        for message in MESSAGES {
+            let mut buf = [0; 3];
+            let len = message.encode(&mut buf);
+            assert_eq!(Message::decode(&buf[..len]), Some(message));
+        }
+
+        let mut buf = [0; 3];
+        assert_eq!(MESSAGES[1].encode(&mut buf), 3);
+        assert_eq!(buf, [0x9F, 127, 1]);
+        assert_eq!(Message::Stop.encode(&mut buf), 1);
+        assert_eq!(buf[0], 0xFC);
+        // note on with a velocity of 0
+        assert_eq!(
+            Message::decode(&[0x92, 40, 0]),
+            Some(Message::NoteOff {
+                channel: 2,
+                note: 40,
+                velocity: 0
+            })
+        );
+        assert_eq!(Message::decode(&[0x92, 40]), None);
+        assert_eq!(Message::decode(&[0xE0, 0, 64]), None);
+        assert_eq!(Message::decode(&[]), None);
+    }
+
+    #[test]
+    fn usb_packets() {
+        for message in MESSAGES {
+            let packet = message.to_usb_packet(1);
+            assert_eq!(packet[0] >> 4, 1);
+            assert_eq!(Message::decode(usb_packet_data(&packet)), Some(message));
+        }
+        assert_eq!(MESSAGES[0].to_usb_packet(0), [0x08, 0x83, 60, 64]);
+        assert_eq!(Message::TimingClock.to_usb_packet(0), [0x0F, 0xF8, 0, 0]);
+    }
+
+    #[test]
+    fn sysex_packets() {
+        for len in 2..=9 {
+            let mut sysex = std::vec![SYSEX_START];
+            sysex.extend((1..len - 1).map(|byte| byte as u8));
+            sysex.push(SYSEX_END);
+
+            let packets: std::vec::Vec<_> = sysex_usb_packets(&sysex, 0).collect();
+            assert_eq!(packets.len(), (len + 2) / 3);
+            let (last, rest) = packets.split_last().unwrap();
+            assert!(rest.iter().all(|packet| packet[0] == CIN_SYSEX));
+            assert_eq!(last[0], CIN_SYSEX_END_1 + (len as u8 - 1) % 3);
+
+            let data: std::vec::Vec<u8> = packets
+                .iter()
+                .flat_map(|packet| usb_packet_data(packet).to_vec())
+                .collect();
+            assert_eq!(data, sysex);
+        }
+    }
+
+    #[test]
+    fn parser_running_status() {
+        let mut parser = Parser::default();
+        // running status, with a clock between the data bytes of a note
+        let messages = parse(&mut parser, &[0x90, 60, 100, 62, 0xF8, 100, 64, 0]);
+        assert_eq!(
+            messages,
+            [
+                Message::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                },
+                Message::TimingClock,
+                Message::NoteOn {
+                    channel: 0,
+                    note: 62,
+                    velocity: 100
+                },
+                Message::NoteOff {
+                    channel: 0,
+                    note: 64,
+                    velocity: 0
+                },
+            ]
+        );
+
+        // system common messages cancel running status, data bytes without
+        // status are ignored
+        assert_eq!(parse(&mut parser, &[0xF3, 1, 60, 100]), []);
+        // unsupported messages with one data byte keep their length
+        assert_eq!(
+            parse(&mut parser, &[0xC0, 5, 0xB1, 7, 100]),
+            [Message::ControlChange {
+                channel: 1,
+                control: 7,
+                value: 100
+            }]
+        );
+    }
+
+    #[test]
+    fn parser_sysex() {
+        let mut parser = Parser::default();
+        let sysex = [SYSEX_START, 0x7D, 1, 2, 3, SYSEX_END];
+        let mut received = None;
+        for byte in sysex {
+            if let Some(Received::Sysex(data)) = parser.push(byte) {
+                received = Some(data.to_vec());
+            }
+        }
+        assert_eq!(received.as_deref(), Some(&sysex[..]));
+
+        // a SysEx message too large for the buffer is dropped
+        let mut parser = Parser::default();
+        let mut received = false;
+        parser.push(SYSEX_START);
+        for _ in 0..SYSEX_MAX_SIZE {
+            received |= parser.push(1).is_some();
+        }
+        received |= parser.push(SYSEX_END).is_some();
+        assert!(!received);
+        assert_eq!(parse(&mut parser, &[0xFA]), [Message::Start]);
+    }
+
+    #[test]
+    fn packet_queue() {
+        let mut queue: PacketQueue<4> = PacketQueue::new();
+        queue.push_message(Message::Start, 0).unwrap();
+        assert!(!queue.push_sysex(&[SYSEX_START, 1, 2, 3, 4, 5, 6, 7, 8, SYSEX_END], 0));
+        assert!(queue.push_sysex(&[SYSEX_START, 1, 2, 3, 4, SYSEX_END], 0));
+        queue.push_message(Message::Stop, 0).unwrap();
+        assert_eq!(queue.push_message(Message::Start, 0), Err(Message::Start));
+
+        // packets stay queued until written
+        let mut buf = [0; 8];
+        assert_eq!(queue.peek(&mut buf), 8);
+        assert_eq!(buf, [0x0F, 0xFA, 0, 0, 0x04, SYSEX_START, 1, 2]);
+        queue.consume(8);
+        assert_eq!(queue.peek(&mut buf), 8);
+        assert_eq!(buf, [0x07, 3, 4, SYSEX_END, 0x0F, 0xFC, 0, 0]);
+        queue.consume(8);
+        assert!(queue.is_empty());
+        assert_eq!(queue.peek(&mut buf), 0);
+    }
+
+    // clock pulses every period milliseconds from start, return the grid of
+    // the steps to prepare and the time of their pulse
+    fn follow(
+        clock: &mut ClockIn,
+        start: u64,
+        pulses: u64,
+        period: u64,
+        lookahead: u64,
+    ) -> std::vec::Vec<(u64, u64)> {
+        (0..pulses)
+            .filter_map(|pulse| {
+                let now = start + pulse * period;
+                clock.pulse(now, 24, lookahead).map(|grid| (grid, now))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clock_in() {
+        // 125 BPM, 480ms steps prepared 250ms ahead of the grid. The clock
+        // is measured while stopped
+        let mut clock = ClockIn::default();
+        assert_eq!(follow(&mut clock, 0, 24, 20, 250), []);
+        clock.start();
+        // the first step is played with its pulse, the next ones are
+        // prepared at the last pulse before their grid minus lookahead
+        assert_eq!(
+            follow(&mut clock, 480, 72, 20, 250),
+            [(480, 480), (960, 700), (1440, 1180), (1920, 1660)]
+        );
+
+        // the period is measured from the second pulse
+        let mut clock = ClockIn::default();
+        clock.start();
+        assert_eq!(
+            follow(&mut clock, 0, 48, 20, 250),
+            [(0, 0), (480, 220), (960, 700)]
+        );
+
+        // steps are prepared right away when lookahead is longer than a step
+        let mut clock = ClockIn::default();
+        clock.start();
+        assert_eq!(
+            follow(&mut clock, 0, 48, 1, 100),
+            [(0, 0), (24, 1), (48, 24)]
+        );
+
+        // a stopped clock prepares no step, continue goes on where it stopped
+        let mut clock = ClockIn::default();
+        clock.start();
+        follow(&mut clock, 0, 11, 20, 250);
+        clock.stop();
+        assert_eq!(clock.pulse(220, 24, 250), None);
+        clock.resume();
+        assert_eq!(clock.pulse(240, 24, 250), Some(500));
+    }
+}
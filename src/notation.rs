@@ -0,0 +1,298 @@
+use core::fmt;
+
+use crate::constants::*;
+use crate::pitch::{Pitch, MAX_PITCH};
+use crate::track::{Gate, Step};
+
+// compact text notation of a pattern, one token per step separated by spaces:
+//
+//   C4 . E4 G4~ - Bb3 x x
+//
+//   C4, Bb3, F#2  note with its octave, C4 being the middle C
+//   x             gate without a specific note
+//   .             rest
+//   -             continuation, the previous note is held through the step
+//   ~             suffix tying a note to the next step
+//
+// Missing steps at the end of the pattern are rests.
+
+// octave written for the octave 0 of a step
+const OCTAVE_OFFSET: i8 = 4;
+const MIN_OCTAVE: i8 = -1;
+const MAX_OCTAVE: i8 = 9;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    UnexpectedChar(char),
+    MissingOctave,
+    InvalidOctave,
+    // note below C-1 or above G9
+    PitchOutOfRange,
+    // continuation without a note to hold
+    NothingToContinue,
+    TooManySteps,
+}
+
+// parse error with the byte position of the offending character in the input
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub position: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::UnexpectedChar(c) => write!(f, "unexpected '{}'", c)?,
+            ErrorKind::MissingOctave => write!(f, "missing octave")?,
+            ErrorKind::InvalidOctave => {
+                write!(f, "octave out of range ({} to {})", MIN_OCTAVE, MAX_OCTAVE)?
+            }
+            ErrorKind::PitchOutOfRange => write!(f, "note out of range (C-1 to G9)")?,
+            ErrorKind::NothingToContinue => write!(f, "no note to continue")?,
+            ErrorKind::TooManySteps => write!(f, "more than {} steps", STEPS_COUNT)?,
+        }
+        write!(f, " at position {}", self.position)
+    }
+}
+
+fn error(position: usize, kind: ErrorKind) -> ParseError {
+    ParseError { position, kind }
+}
+
+// parse a pattern written in the text notation
+pub fn parse(text: &str) -> Result<[Step; STEPS_COUNT], ParseError> {
+    let mut steps = [Step::new(); STEPS_COUNT];
+    let mut count = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((position, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if count == STEPS_COUNT {
+            return Err(error(position, ErrorKind::TooManySteps));
+        }
+
+        let mut step = Step::new();
+        match c {
+            '.' => {}
+            'x' => step.gate = Gate::ON,
+            '-' => match count.checked_sub(1).map(|i| &mut steps[i]) {
+                Some(previous) if previous.gate == Gate::ON => {
+                    previous.tie = true;
+                    step = Step {
+                        tie: false,
+                        ..*previous
+                    };
+                }
+                _ => return Err(error(position, ErrorKind::NothingToContinue)),
+            },
+            'A'..='G' => {
+                let mut semitone = match c {
+                    'C' => 0,
+                    'D' => 2,
+                    'E' => 4,
+                    'F' => 5,
+                    'G' => 7,
+                    'A' => 9,
+                    _ => 11,
+                };
+                let mut end = position + c.len_utf8();
+                match chars.peek() {
+                    Some((_, 'b')) => {
+                        chars.next();
+                        semitone -= 1;
+                        end += 1;
+                    }
+                    Some((_, '#')) => {
+                        chars.next();
+                        semitone += 1;
+                        end += 1;
+                    }
+                    _ => {}
+                }
+                let octave = parse_octave(&mut chars, end)?;
+                let number = (octave as i16 + 1) * 12 + semitone as i16;
+                if !(0..=MAX_PITCH as i16).contains(&number) {
+                    return Err(error(position, ErrorKind::PitchOutOfRange));
+                }
+                step = Step::new();
+                step.set_pitch(Pitch::new(number as u8));
+                step.gate = Gate::ON;
+            }
+            _ => return Err(error(position, ErrorKind::UnexpectedChar(c))),
+        }
+
+        if let Some((tie, '~')) = chars.peek() {
+            if step.gate == Gate::OFF {
+                return Err(error(*tie, ErrorKind::UnexpectedChar('~')));
+            }
+            chars.next();
+            step.tie = true;
+        }
+        // tokens must be separated by spaces
+        if let Some((position, c)) = chars.peek() {
+            if !c.is_whitespace() {
+                return Err(error(*position, ErrorKind::UnexpectedChar(*c)));
+            }
+        }
+
+        steps[count] = step;
+        count += 1;
+    }
+    Ok(steps)
+}
+
+fn parse_octave(
+    chars: &mut core::iter::Peekable<core::str::CharIndices>,
+    position: usize,
+) -> Result<i8, ParseError> {
+    let negative = matches!(chars.peek(), Some((_, '-')));
+    if negative {
+        chars.next();
+    }
+    match chars.next() {
+        Some((position, c)) if c.is_ascii_digit() => {
+            let octave = c as i8 - b'0' as i8;
+            let octave = if negative { -octave } else { octave };
+            if !(MIN_OCTAVE..=MAX_OCTAVE).contains(&octave)
+                || matches!(chars.peek(), Some((_, c)) if c.is_ascii_digit())
+            {
+                return Err(error(position, ErrorKind::InvalidOctave));
+            }
+            Ok(octave)
+        }
+        Some((position, _)) => Err(error(position, ErrorKind::MissingOctave)),
+        None => Err(error(
+            position + negative as usize,
+            ErrorKind::MissingOctave,
+        )),
+    }
+}
+
+// pattern formatter, gate tracks are written with gates only
+pub struct Notation<'a> {
+    steps: &'a [Step],
+    gates_only: bool,
+}
+
+impl Notation<'_> {
+    pub fn new(steps: &[Step], gates_only: bool) -> Notation<'_> {
+        Notation { steps, gates_only }
+    }
+
+    // true if the step holds the note of the previous step
+    fn is_continuation(&self, index: usize) -> bool {
+        let step = &self.steps[index];
+        match index.checked_sub(1).map(|i| &self.steps[i]) {
+            Some(previous) => {
+                previous.gate == Gate::ON
+                    && previous.tie
+                    && step.gate == Gate::ON
+                    && (self.gates_only
+                        || (previous.note == step.note && previous.octave == step.octave))
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for Notation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            if step.gate == Gate::OFF {
+                write!(f, ".")?;
+                continue;
+            }
+
+            if self.is_continuation(i) {
+                write!(f, "-")?;
+            } else if self.gates_only {
+                write!(f, "x")?;
+            } else {
+                write!(
+                    f,
+                    "{}{}",
+                    step.note.name(),
+                    step.octave as i16 + OCTAVE_OFFSET as i16
+                )?;
+            }
+
+            // ties into a continuation are implied by the continuation
+            let continued = i + 1 < self.steps.len() && self.is_continuation(i + 1);
+            if step.tie && !continued {
+                write!(f, "~")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::Note;
+    use std::string::{String, ToString};
+
+    fn format(text: &str, gates_only: bool) -> String {
+        Notation::new(&parse(text).unwrap(), gates_only).to_string()
+    }
+
+    fn parse_error(text: &str) -> (usize, ErrorKind) {
+        let error = parse(text).unwrap_err();
+        (error.position, error.kind)
+    }
+
+    #[test]
+    fn parse_format() {
+        let patterns = [
+            "C4 . E4 G4 - Bb3 . Db4~",
+            "Bb-1 C9 G9 Gb2~ . . . .",
+            "C4~ D4 . . . . . .",
+            ". . . . . . . .",
+        ];
+        for pattern in patterns {
+            assert_eq!(format(pattern, false), pattern);
+        }
+        assert_eq!(format("x . x~ - - .", true), "x . x - - . . .");
+        // ties into a continuation and enharmonic notes are normalized
+        assert_eq!(format("C4 . E4 G4~ - A#3", false), "C4 . E4 G4 - Bb3 . .");
+        assert_eq!(format("Cb4 B#3 C#4", false), "B3 C4 Db4 . . . . .");
+        // a tie after a rest is fine
+        assert_eq!(format(". x~ -", true), ". x - . . . . .");
+
+        let steps = parse("G4~ - Bb-1").unwrap();
+        assert!(steps[0].tie && !steps[1].tie);
+        assert_eq!((steps[1].gate, steps[1].note), (Gate::ON, Note::G));
+        assert_eq!((steps[2].note, steps[2].octave), (Note::Bb, -5));
+        assert_eq!(steps[3].gate, Gate::OFF);
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(parse_error("C10"), (1, ErrorKind::InvalidOctave));
+        assert_eq!(parse_error("C4 H4"), (3, ErrorKind::UnexpectedChar('H')));
+        assert_eq!(parse_error("- C4"), (0, ErrorKind::NothingToContinue));
+        assert_eq!(parse_error(". -"), (2, ErrorKind::NothingToContinue));
+        assert_eq!(parse_error("C4~~"), (3, ErrorKind::UnexpectedChar('~')));
+        assert_eq!(parse_error(". x .~"), (5, ErrorKind::UnexpectedChar('~')));
+        assert_eq!(
+            parse_error("C4 . . . . . . . x~"),
+            (17, ErrorKind::TooManySteps)
+        );
+        assert_eq!(parse_error("Bb-2"), (3, ErrorKind::InvalidOctave));
+        assert_eq!(parse_error("C4 G#9"), (3, ErrorKind::PitchOutOfRange));
+        assert_eq!(parse_error("Cb-1"), (0, ErrorKind::PitchOutOfRange));
+        assert_eq!(parse_error("Bb-"), (3, ErrorKind::MissingOctave));
+        assert_eq!(parse_error("C# D4"), (2, ErrorKind::MissingOctave));
+        assert_eq!(parse_error("C4."), (2, ErrorKind::UnexpectedChar('.')));
+        assert_eq!(
+            parse("C4 D").unwrap_err().to_string(),
+            "missing octave at position 4"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use core::fmt;
+use core::ops::{Add, Sub};
+
+use crate::constants::*;
+use crate::track::Note;
+
+pub const MAX_PITCH: u8 = 127;
+// octaves of the MIDI range, octave 0 being the octave of middle C
+pub const MIN_OCTAVE: i8 = -5;
+pub const MAX_OCTAVE: i8 = 5;
+// MIDI note number of middle C, the C of octave 0
+const MIDDLE_C: i16 = 60;
+
+// pitch as a MIDI note number, from C-1 (0) to G9 (127)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pitch(u8);
+
+impl Default for Pitch {
+    fn default() -> Self {
+        Self::new(MIDDLE_C as u8)
+    }
+}
+
+impl Pitch {
+    pub fn new(number: u8) -> Pitch {
+        Pitch(number.min(MAX_PITCH))
+    }
+
+    // pitch of a note, octave 0 being the octave of middle C. Pitches out of
+    // the MIDI range are clamped
+    pub fn from_note(note: Note, octave: i8) -> Pitch {
+        Pitch::clamped(MIDDLE_C + octave as i16 * 12 + note.semitone() as i16)
+    }
+
+    fn clamped(number: i16) -> Pitch {
+        Pitch(number.clamp(0, MAX_PITCH as i16) as u8)
+    }
+
+    pub fn midi(&self) -> u8 {
+        self.0
+    }
+
+    pub fn note(&self) -> Note {
+        Note::from_semitone(self.0 % 12)
+    }
+
+    // octave 0 is the octave of middle C
+    pub fn octave(&self) -> i8 {
+        ((self.0 as i16 - MIDDLE_C).div_euclid(12)) as i8
+    }
+
+    // DAC value of the pitch CV at 1V/oct, pitches out of the range of the
+    // DAC being clamped
+    pub fn cv(&self) -> u16 {
+        let semitones = self.0.saturating_sub(CV_ZERO_PITCH) as u32;
+        ((semitones * CV_COUNTS_PER_OCTAVE + 6) / 12).min(DAC_MAX as u32) as u16
+    }
+
+    // shift by a number of semitones, clamped to the MIDI range
+    pub fn transpose(&self, semitones: i16) -> Pitch {
+        Pitch::clamped(self.0 as i16 + semitones)
+    }
+}
+
+impl Add<i8> for Pitch {
+    type Output = Pitch;
+
+    fn add(self, semitones: i8) -> Pitch {
+        self.transpose(semitones as i16)
+    }
+}
+
+impl Sub<i8> for Pitch {
+    type Output = Pitch;
+
+    fn sub(self, semitones: i8) -> Pitch {
+        self.transpose(-(semitones as i16))
+    }
+}
+
+// interval in semitones
+impl Sub for Pitch {
+    type Output = i16;
+
+    fn sub(self, other: Pitch) -> i16 {
+        self.0 as i16 - other.0 as i16
+    }
+}
+
+// scientific pitch notation, middle C being C4
+impl fmt::Display for Pitch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.note().name(), self.0 as i16 / 12 - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octave_and_transpose() {
+        let c = Pitch::from_note(Note::C, 0);
+        assert_eq!(c.midi(), 60);
+        assert_eq!(Pitch::from_note(Note::C, 1) - c, 12);
+        assert_eq!((c + 14).note(), Note::D);
+        assert_eq!((c + 14).octave(), 1);
+        assert_eq!((c - 1).octave(), -1);
+        assert_eq!(Pitch::from_note(Note::G, 6).midi(), MAX_PITCH);
+        assert_eq!(Pitch::from_note(Note::C, -6).midi(), 0);
+        assert_eq!(Pitch::new(0).octave(), MIN_OCTAVE);
+        assert_eq!(Pitch::new(MAX_PITCH).octave(), MAX_OCTAVE);
+        assert_eq!(std::format!("{}", c + 22), "Bb5");
+    }
+
+    #[test]
+    fn cv_follows_one_volt_per_octave() {
+        let cv = |note, octave| Pitch::from_note(note, octave).cv();
+        assert_eq!(cv(Note::C, -2), 0);
+        assert_eq!(cv(Note::Db, -2), 68);
+        assert_eq!(cv(Note::C, -1), CV_COUNTS_PER_OCTAVE as u16);
+        // the upper C of the note keys is an octave above the lower one
+        assert_eq!(cv(Note::C, 1) - cv(Note::C, 0), CV_COUNTS_PER_OCTAVE as u16);
+        assert!(cv(Note::Bb, 0) > cv(Note::Ab, 0));
+        assert_eq!(cv(Note::C, 3), DAC_MAX);
+        // out of the range of the DAC
+        assert_eq!(cv(Note::C, -4), 0);
+        assert_eq!(cv(Note::G, 5), DAC_MAX);
+    }
+}
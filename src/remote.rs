@@ -0,0 +1,466 @@
+use heapless::Vec;
+
+use crate::constants::*;
+use crate::track::{Step, STEP_SIZE, TRACK_SIZE};
+
+// binary remote control protocol, used by host editors over the console
+// serial port:
+//
+//   00 <COBS encoded payload> 00
+//   payload: <command> <data...> <CRC-16 CCITT, little endian>
+//
+// Frames are delimited by zero bytes, which never appear in console text.
+// Every request is answered with its reply, an ack or a nak. Replies use the
+// request command with the high bit set.
+
+// requests
+const GET_STEP: u8 = 0x01;
+const SET_STEP: u8 = 0x02;
+const GET_TRACK: u8 = 0x03;
+const SET_TRACK: u8 = 0x04;
+const GET_SETTINGS: u8 = 0x05;
+const SET_SETTINGS: u8 = 0x06;
+const SUBSCRIBE: u8 = 0x07;
+const TRANSPORT: u8 = 0x08;
+
+// replies and events
+const REPLY: u8 = 0x80;
+const ACK: u8 = 0x40;
+const NAK: u8 = 0x41;
+const PLAYHEAD: u8 = 0xC0;
+
+const CRC_SIZE: usize = 2;
+// largest payload, a track reply
+const PAYLOAD_SIZE: usize = 2 + TRACK_SIZE + CRC_SIZE;
+// largest frame with its COBS overhead and delimiters
+pub const FRAME_SIZE: usize = PAYLOAD_SIZE + PAYLOAD_SIZE / 254 + 1 + 2;
+
+pub type Payload = Vec<u8, PAYLOAD_SIZE>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RemoteError {
+    Malformed,
+    UnknownCommand,
+    InvalidArgument,
+    // a request is still being executed
+    Busy,
+}
+
+impl RemoteError {
+    fn code(&self) -> u8 {
+        match self {
+            RemoteError::Malformed => 1,
+            RemoteError::UnknownCommand => 2,
+            RemoteError::InvalidArgument => 3,
+            RemoteError::Busy => 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transport {
+    Stop,
+    Start,
+    Continue,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Request<'a> {
+    GetStep {
+        track: usize,
+        step: usize,
+    },
+    SetStep {
+        track: usize,
+        step: usize,
+        value: Step,
+    },
+    GetTrack(usize),
+    // serialized track, as in pattern dumps
+    SetTrack(usize, &'a [u8]),
+    GetSettings,
+    SetSettings {
+        bpm: u16,
+        gate_length: u8,
+        current_track: usize,
+    },
+    Subscribe(bool),
+    Transport(Transport),
+}
+
+impl Request<'_> {
+    // decode a request from a frame payload, without its CRC
+    pub fn decode(payload: &[u8]) -> Result<Request<'_>, RemoteError> {
+        let (command, data) = payload.split_first().ok_or(RemoteError::Malformed)?;
+        let request = match (*command, data) {
+            (GET_STEP, [track, step]) => Request::GetStep {
+                track: track_index(*track)?,
+                step: step_index(*step)?,
+            },
+            (SET_STEP, [track, step, value @ ..]) if value.len() >= STEP_SIZE => Request::SetStep {
+                track: track_index(*track)?,
+                step: step_index(*step)?,
+                value: Step::from_bytes(value).ok_or(RemoteError::InvalidArgument)?,
+            },
+            (GET_TRACK, [track]) => Request::GetTrack(track_index(*track)?),
+            (SET_TRACK, [track, bytes @ ..]) if !bytes.is_empty() => {
+                Request::SetTrack(track_index(*track)?, bytes)
+            }
+            (GET_SETTINGS, []) => Request::GetSettings,
+            (SET_SETTINGS, [bpm_low, bpm_high, gate_length, current_track]) => {
+                let bpm = u16::from_le_bytes([*bpm_low, *bpm_high]);
+                if !(MIN_BPM..=MAX_BPM).contains(&bpm) || !(1..=100).contains(gate_length) {
+                    return Err(RemoteError::InvalidArgument);
+                }
+                Request::SetSettings {
+                    bpm,
+                    gate_length: *gate_length,
+                    current_track: track_index(*current_track)?,
+                }
+            }
+            (SUBSCRIBE, [enabled]) => Request::Subscribe(*enabled != 0),
+            (TRANSPORT, [transport]) => Request::Transport(match transport {
+                0 => Transport::Stop,
+                1 => Transport::Start,
+                2 => Transport::Continue,
+                _ => return Err(RemoteError::InvalidArgument),
+            }),
+            (
+                GET_STEP | SET_STEP | GET_TRACK | SET_TRACK | GET_SETTINGS | SET_SETTINGS
+                | SUBSCRIBE | TRANSPORT,
+                _,
+            ) => return Err(RemoteError::Malformed),
+            _ => return Err(RemoteError::UnknownCommand),
+        };
+        Ok(request)
+    }
+}
+
+fn track_index(index: u8) -> Result<usize, RemoteError> {
+    match index as usize {
+        index if index < TRACKS_COUNT => Ok(index),
+        _ => Err(RemoteError::InvalidArgument),
+    }
+}
+
+fn step_index(index: u8) -> Result<usize, RemoteError> {
+    match index as usize {
+        index if index < STEPS_COUNT => Ok(index),
+        _ => Err(RemoteError::InvalidArgument),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Reply {
+    // request command
+    Ack(u8),
+    Nak(u8, RemoteError),
+    Step(usize, usize, Step),
+    Track(usize, [u8; TRACK_SIZE]),
+    Settings {
+        bpm: u16,
+        gate_length: u8,
+        current_track: usize,
+    },
+    // cursor of each track, sent on every step to subscribed hosts
+    Playhead([usize; TRACKS_COUNT]),
+}
+
+impl Reply {
+    // encode the reply into a frame, return the frame length
+    pub fn encode(&self, out: &mut [u8; FRAME_SIZE]) -> usize {
+        let mut payload = Payload::new();
+        // the payload always fits, the buffer is sized for the largest reply
+        match self {
+            Reply::Ack(command) => payload.extend_from_slice(&[ACK, *command]),
+            Reply::Nak(command, error) => payload.extend_from_slice(&[NAK, *command, error.code()]),
+            Reply::Step(track, step, value) => payload
+                .extend_from_slice(&[REPLY | GET_STEP, *track as u8, *step as u8])
+                .and_then(|_| payload.extend_from_slice(&value.to_bytes())),
+            Reply::Track(track, bytes) => payload
+                .extend_from_slice(&[REPLY | GET_TRACK, *track as u8])
+                .and_then(|_| payload.extend_from_slice(bytes)),
+            Reply::Settings {
+                bpm,
+                gate_length,
+                current_track,
+            } => {
+                let bpm = bpm.to_le_bytes();
+                payload.extend_from_slice(&[
+                    REPLY | GET_SETTINGS,
+                    bpm[0],
+                    bpm[1],
+                    *gate_length,
+                    *current_track as u8,
+                ])
+            }
+            Reply::Playhead(cursors) => {
+                let mut data = [PLAYHEAD; 1 + TRACKS_COUNT];
+                for (byte, cursor) in data[1..].iter_mut().zip(cursors) {
+                    *byte = *cursor as u8;
+                }
+                payload.extend_from_slice(&data)
+            }
+        }
+        .ok();
+        payload
+            .extend_from_slice(&crc16(&payload).to_le_bytes())
+            .ok();
+
+        out[0] = 0;
+        let len = 1 + cobs_encode(&payload, &mut out[1..]);
+        out[len] = 0;
+        len + 1
+    }
+}
+
+// CRC-16 CCITT (polynomial 0x1021, initial value 0xFFFF)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// consistent overhead byte stuffing, return the encoded length
+fn cobs_encode(data: &[u8], out: &mut [u8]) -> usize {
+    let mut code_index = 0;
+    let mut code = 1;
+    let mut len = 1;
+    for byte in data {
+        if *byte != 0 {
+            out[len] = *byte;
+            len += 1;
+            code += 1;
+        }
+        if *byte == 0 || code == 0xFF {
+            out[code_index] = code;
+            code_index = len;
+            code = 1;
+            len += 1;
+        }
+    }
+    out[code_index] = code;
+    len
+}
+
+// return None if the data is not valid COBS or does not fit
+fn cobs_decode(data: &[u8], out: &mut Payload) -> Option<()> {
+    let mut i = 0;
+    while i < data.len() {
+        let code = data[i] as usize;
+        let end = i + code;
+        if code == 0 || end > data.len() {
+            return None;
+        }
+        out.extend_from_slice(&data[i + 1..end]).ok()?;
+        if code < 0xFF && end < data.len() {
+            out.push(0).ok()?;
+        }
+        i = end;
+    }
+    Some(())
+}
+
+// input of the console serial port
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    // byte outside of a frame, for the text console
+    Text(u8),
+    Frame(Result<Payload, RemoteError>),
+}
+
+// split the serial input into frames and console text. A zero byte starts a
+// frame, which ends with the next zero byte
+#[derive(Clone, Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8, FRAME_SIZE>,
+    receiving: bool,
+    overflow: bool,
+}
+
+impl FrameDecoder {
+    // feed a received byte, return the byte back when it is not part of a
+    // frame or the payload once a frame is complete and valid
+    pub fn push(&mut self, byte: u8) -> Option<Input> {
+        if !self.receiving {
+            if byte == 0 {
+                self.receiving = true;
+                return None;
+            }
+            return Some(Input::Text(byte));
+        }
+        if byte != 0 {
+            self.overflow |= self.buffer.push(byte).is_err();
+            return None;
+        }
+        // consecutive delimiters
+        if self.buffer.is_empty() && !self.overflow {
+            return None;
+        }
+
+        self.receiving = false;
+        let overflow = core::mem::take(&mut self.overflow);
+        let mut payload = Payload::new();
+        let valid = !overflow && cobs_decode(&self.buffer, &mut payload).is_some();
+        self.buffer.clear();
+        if !valid || payload.len() <= CRC_SIZE {
+            return Some(Input::Frame(Err(RemoteError::Malformed)));
+        }
+
+        let len = payload.len() - CRC_SIZE;
+        let crc = u16::from_le_bytes([payload[len], payload[len + 1]]);
+        if crc16(&payload[..len]) != crc {
+            return Some(Input::Frame(Err(RemoteError::Malformed)));
+        }
+        payload.truncate(len);
+        Some(Input::Frame(Ok(payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::Track;
+
+    // the same frames are checked by the host client, tools/remote
+    const ACK_FRAME: [u8; 7] = [0x00, 0x05, 0x40, 0x02, 0x81, 0x30, 0x00];
+    const PLAYHEAD_FRAME: [u8; 14] = [
+        0x00, 0x02, 0xC0, 0x0A, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0xAA, 0xDE, 0x00,
+    ];
+    const SETTINGS_FRAME: [u8; 10] = [0x00, 0x03, 0x85, 0x78, 0x05, 0x32, 0x03, 0xA9, 0x84, 0x00];
+    const NAK_FRAME: [u8; 8] = [0x00, 0x06, 0x41, 0x05, 0x03, 0x97, 0x29, 0x00];
+    const STEP_BYTES: [u8; STEP_SIZE] = [1, 9, 0xFF, 200, 1, 50, 3, 75, 2, 0x34, 1, 0xF6];
+
+    fn encode(reply: Reply) -> std::vec::Vec<u8> {
+        let mut out = [0; FRAME_SIZE];
+        let len = reply.encode(&mut out);
+        out[..len].to_vec()
+    }
+
+    fn decode(decoder: &mut FrameDecoder, bytes: &[u8]) -> std::vec::Vec<Input> {
+        bytes
+            .iter()
+            .filter_map(|byte| decoder.push(*byte))
+            .collect()
+    }
+
+    fn frame(payload: &[u8]) -> std::vec::Vec<u8> {
+        let mut data = payload.to_vec();
+        data.extend_from_slice(&crc16(payload).to_le_bytes());
+        let mut out = [0; FRAME_SIZE];
+        let len = cobs_encode(&data, &mut out[1..]);
+        out[..len + 2].to_vec()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn reply_frames() {
+        assert_eq!(encode(Reply::Ack(SET_STEP)), ACK_FRAME);
+        assert_eq!(
+            encode(Reply::Playhead([0, 1, 2, 3, 4, 5, 6, 7])),
+            PLAYHEAD_FRAME
+        );
+        let settings = Reply::Settings {
+            bpm: 120,
+            gate_length: 50,
+            current_track: 3,
+        };
+        assert_eq!(encode(settings), SETTINGS_FRAME);
+        assert_eq!(
+            encode(Reply::Nak(GET_SETTINGS, RemoteError::InvalidArgument)),
+            NAK_FRAME
+        );
+
+        // the largest reply fits and decodes back
+        let track = Track::new().to_bytes();
+        let frame = encode(Reply::Track(7, track));
+        let mut decoder = FrameDecoder::default();
+        let mut expected = Payload::from_slice(&[REPLY | GET_TRACK, 7]).unwrap();
+        expected.extend_from_slice(&track).unwrap();
+        assert_eq!(decode(&mut decoder, &frame), [Input::Frame(Ok(expected))]);
+    }
+
+    #[test]
+    fn frames_and_text() {
+        let mut decoder = FrameDecoder::default();
+        let mut bytes = std::vec![b'b', b'p', b'm'];
+        bytes.extend_from_slice(&frame(&[GET_SETTINGS]));
+        bytes.extend_from_slice(&ACK_FRAME[..4]);
+        bytes.push(0);
+        bytes.push(b'\n');
+        assert_eq!(
+            decode(&mut decoder, &bytes),
+            [
+                Input::Text(b'b'),
+                Input::Text(b'p'),
+                Input::Text(b'm'),
+                Input::Frame(Ok(Payload::from_slice(&[GET_SETTINGS]).unwrap())),
+                Input::Frame(Err(RemoteError::Malformed)),
+                Input::Text(b'\n'),
+            ]
+        );
+
+        // frames longer than the buffer are rejected
+        let mut bytes = std::vec![0; FRAME_SIZE + 2];
+        bytes[1..FRAME_SIZE + 1].fill(0x01);
+        assert_eq!(
+            decode(&mut decoder, &bytes),
+            [Input::Frame(Err(RemoteError::Malformed))]
+        );
+    }
+
+    #[test]
+    fn requests() {
+        let mut payload = std::vec![SET_STEP, 1, 2];
+        payload.extend_from_slice(&STEP_BYTES);
+        assert_eq!(
+            Request::decode(&payload),
+            Ok(Request::SetStep {
+                track: 1,
+                step: 2,
+                value: Step::from_bytes(&STEP_BYTES).unwrap(),
+            })
+        );
+        assert_eq!(
+            Step::from_bytes(&STEP_BYTES).unwrap().to_bytes(),
+            STEP_BYTES
+        );
+
+        assert_eq!(
+            Request::decode(&[SET_SETTINGS, 120, 0, 50, 3]),
+            Ok(Request::SetSettings {
+                bpm: 120,
+                gate_length: 50,
+                current_track: 3,
+            })
+        );
+        assert_eq!(
+            Request::decode(&[TRANSPORT, 2]),
+            Ok(Request::Transport(Transport::Continue))
+        );
+        assert_eq!(
+            Request::decode(&[GET_STEP, 0, STEPS_COUNT as u8]),
+            Err(RemoteError::InvalidArgument)
+        );
+        assert_eq!(
+            Request::decode(&[SET_SETTINGS, 120, 0, 0, 3]),
+            Err(RemoteError::InvalidArgument)
+        );
+        assert_eq!(Request::decode(&[GET_TRACK]), Err(RemoteError::Malformed));
+        assert_eq!(Request::decode(&[0x30]), Err(RemoteError::UnknownCommand));
+        assert_eq!(Request::decode(&[]), Err(RemoteError::Malformed));
+    }
+}
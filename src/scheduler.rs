@@ -0,0 +1,714 @@
+use heapless::Vec;
+
+use crate::constants::*;
+use crate::glide::Glide;
+use crate::midi::Message;
+use crate::pitch::Pitch;
+use crate::settings::Settings;
+use crate::track::{Gate, Track, TrackMode};
+
+// events queued for a step: on each track the release of the note held by a
+// tie, the pitch and the gate of the step
+pub const STEP_EVENTS_COUNT: usize = 3 * TRACKS_COUNT;
+
+// slots kept for the release of the notes held by ties, one per track
+const RELEASES_COUNT: usize = TRACKS_COUNT;
+
+// events waiting to be fired, at worst on each track: the events of the step
+// being queued and of the previous one, due at the same time, and the release
+// and next repeat of the gate being played. The release of a gate takes the
+// slot of its opening, so that no release is ever dropped
+pub const EVENTS_COUNT: usize = TRACKS_COUNT * (2 * 3 + 2) + RELEASES_COUNT;
+
+// output of a track, the MIDI channel being the track index and the DAC the
+// output of the first two tracks
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Output {
+    // open the gate: play the note unless it is held through a tie and set
+    // the DAC of gate and velocity tracks to level. The gate is closed after
+    // length milliseconds, or stays open for tied steps, and opened again
+    // every interval milliseconds for the repeats left
+    GateOn {
+        note: u8,
+        velocity: u8,
+        legato: bool,
+        level: Option<u16>,
+        length: Option<u16>,
+        interval: u16,
+        repeats: u8,
+    },
+    // close the gate: release the note and reset the DAC of gate and
+    // velocity tracks
+    GateOff {
+        note: Option<u8>,
+        level: bool,
+    },
+    // set the DAC of a CV track, ramping over a glide time in milliseconds
+    Pitch {
+        value: u16,
+        glide: u16,
+    },
+}
+
+// output of a track at a time in milliseconds
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Event {
+    pub at: u64,
+    pub index: usize,
+    pub output: Output,
+}
+
+// outputs prepared ahead of time, fired from the timer interrupt once due
+pub struct Scheduler {
+    // sorted latest first, the next event being popped from the end
+    events: Vec<Event, EVENTS_COUNT>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler { events: Vec::new() }
+    }
+
+    // queue an event, events at the same time are fired in the order they
+    // were queued. Return the event if the queue is full, slots being kept for
+    // the releases of the notes held by ties
+    pub fn push(&mut self, event: Event) -> Result<(), Event> {
+        let release = matches!(event.output, Output::GateOff { .. });
+        if !release && self.events.len() >= EVENTS_COUNT - RELEASES_COUNT {
+            return Err(event);
+        }
+        let position = self
+            .events
+            .iter()
+            .position(|queued| queued.at <= event.at)
+            .unwrap_or(self.events.len());
+        self.events.insert(position, event)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    // pop the next event due at a time in milliseconds, the release and
+    // next repeat of a gate being queued when it is opened. The release always
+    // fits in the slot of the gate, a repeat being dropped if the queue is full
+    pub fn next(&mut self, now: u64) -> Option<Event> {
+        if self.events.last()?.at > now {
+            return None;
+        }
+        let event = self.events.pop()?;
+        if let Output::GateOn {
+            note,
+            velocity,
+            level,
+            length: Some(length),
+            interval,
+            repeats,
+            ..
+        } = event.output
+        {
+            self.push(Event {
+                at: event.at + length as u64,
+                output: Output::GateOff {
+                    note: Some(note),
+                    level: level.is_some(),
+                },
+                ..event
+            })
+            .ok();
+            if repeats > 0 {
+                self.push(Event {
+                    at: event.at + interval as u64,
+                    output: Output::GateOn {
+                        note,
+                        velocity,
+                        legato: false,
+                        level,
+                        length: Some(length),
+                        interval,
+                        repeats: repeats - 1,
+                    },
+                    ..event
+                })
+                .ok();
+            }
+        }
+        Some(event)
+    }
+
+    // fire the events due at a time in milliseconds and ramp the pitch of the
+    // gliding outputs. Notes are sent through send, and the values of the DAC
+    // outputs returned, the last value of each output being the one written
+    pub fn fire(
+        &mut self,
+        now: u64,
+        glides: &mut [Glide; DAC_COUNT],
+        mut send: impl FnMut(Message),
+    ) -> [Option<u16>; DAC_COUNT] {
+        let mut values = [None; DAC_COUNT];
+        while let Some(event) = self.next(now) {
+            let (index, channel) = (event.index, event.index as u8);
+            match event.output {
+                Output::GateOn {
+                    note,
+                    velocity,
+                    legato,
+                    level,
+                    ..
+                } => {
+                    if let (Some(value), Some(level)) = (values.get_mut(index), level) {
+                        *value = Some(level);
+                    }
+                    if !legato {
+                        send(Message::NoteOn {
+                            channel,
+                            note,
+                            velocity,
+                        });
+                    }
+                }
+                Output::GateOff { note, level } => {
+                    if let (Some(value), true) = (values.get_mut(index), level) {
+                        *value = Some(0);
+                    }
+                    if let Some(note) = note {
+                        send(Message::NoteOff {
+                            channel,
+                            note,
+                            velocity: 0,
+                        });
+                    }
+                }
+                Output::Pitch { value, glide } => {
+                    if let Some(ramp) = glides.get_mut(index) {
+                        ramp.set_target(value, now, glide as u64);
+                        values[index] = Some(ramp.value_at(now));
+                    }
+                }
+            }
+        }
+
+        for (value, glide) in values.iter_mut().zip(glides.iter_mut()) {
+            if let Some(ramp) = glide.update(now) {
+                *value = Some(ramp);
+            }
+        }
+        values
+    }
+}
+
+// offset in milliseconds of a step from the grid
+fn step_offset(track: &mut Track, index: usize, settings: &Settings) -> i64 {
+    let swing = track.get_step_swing(index, settings.get_swing());
+    settings.offset_ms(swing, track.get_step(index).nudge)
+}
+
+// output events of the next step of a track on the grid at instant, or the
+// release of the note held by a stopped track
+pub fn step_events(
+    index: usize,
+    track: &mut Track,
+    held: &mut Option<u8>,
+    instant: u64,
+    settings: &Settings,
+    events: &mut Vec<Event, STEP_EVENTS_COUNT>,
+) {
+    // gate and velocity tracks reset their DAC when the gate closes
+    let mode = track.get_mode();
+    let level = mode != TrackMode::CV;
+    let mut push = |at: u64, output: Output| {
+        events.push(Event { at, index, output }).ok();
+    };
+
+    if !track.is_playing() {
+        if let Some(note) = held.take() {
+            push(
+                instant,
+                Output::GateOff {
+                    note: Some(note),
+                    level,
+                },
+            );
+        }
+        return;
+    }
+    track.tick();
+
+    // the step lasts until the next one, which may be played early
+    let (cursor, next) = (track.get_cursor(), track.get_next_cursor());
+    let offset = step_offset(track, cursor, settings);
+    let next = step_offset(track, next, settings);
+    let length = (settings.step_length_ms() as i64 + next - offset).max(0) as u64;
+    let at = (instant as i64 + offset).max(0) as u64;
+    let step = track.get_playing_step();
+
+    // one MIDI channel per track, a note held by a tie is not triggered
+    // again and is released otherwise
+    let note = step.midi_note();
+    let tied = held.take();
+    let legato = step.gate == Gate::ON && tied == Some(note);
+    if let (Some(tied), false) = (tied, legato) {
+        push(
+            at,
+            Output::GateOff {
+                note: Some(tied),
+                level: level && step.gate == Gate::OFF,
+            },
+        );
+    }
+
+    // sliding steps ramp the pitch to their note over the glide time
+    if mode == TrackMode::CV {
+        let glide = if step.slide && step.gate == Gate::ON {
+            track.get_glide()
+        } else {
+            0
+        };
+        push(
+            at,
+            Output::Pitch {
+                value: step.pitch().cv(),
+                glide,
+            },
+        );
+    }
+
+    if step.gate == Gate::ON {
+        let level = match mode {
+            TrackMode::GATE => Some(4080),
+            TrackMode::VELOCITY => Some(step.velocity_cv()),
+            TrackMode::CV => None,
+        };
+        // the gate of a tied step stays open until the next step, other
+        // steps play their gates evenly spread over the step
+        let (gate_length, interval, repeats) = if step.is_tied() {
+            *held = Some(note);
+            (None, 0, 0)
+        } else {
+            let percent = step.gate_percent(settings.get_gate_length());
+            (
+                Some(settings.gate_ms(percent, step.ratchets, length) as u16),
+                settings.ratchet_ms(step.ratchets, length) as u16,
+                step.ratchets.saturating_sub(1),
+            )
+        };
+        push(
+            at,
+            Output::GateOn {
+                note,
+                velocity: step.midi_velocity(),
+                legato,
+                level,
+                length: gate_length,
+                interval,
+                repeats,
+            },
+        );
+    }
+}
+
+// events playing a note entered on the keyboard at a time in milliseconds,
+// on the pitch output and MIDI channel of a CV track for the gate length
+pub fn audition_events(index: usize, pitch: Pitch, now: u64, settings: &Settings) -> [Event; 2] {
+    let length = settings.gate_length_ms() as u16;
+    [
+        Event {
+            at: now,
+            index,
+            output: Output::Pitch {
+                value: pitch.cv(),
+                glide: 0,
+            },
+        },
+        Event {
+            at: now,
+            index,
+            output: Output::GateOn {
+                note: pitch.midi(),
+                velocity: 127,
+                legato: false,
+                level: None,
+                length: Some(length),
+                interval: length,
+                repeats: 0,
+            },
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::ClockIn;
+    use crate::track::{Note, Step};
+
+    fn gate(at: u64, index: usize, length: u16, interval: u16, repeats: u8) -> Event {
+        Event {
+            at,
+            index,
+            output: Output::GateOn {
+                note: 60,
+                velocity: 100,
+                legato: false,
+                level: Some(4080),
+                length: Some(length),
+                interval,
+                repeats,
+            },
+        }
+    }
+
+    fn pitch(at: u64) -> Event {
+        Event {
+            at,
+            index: 0,
+            output: Output::Pitch { value: 0, glide: 0 },
+        }
+    }
+
+    fn release(at: u64, note: u8) -> Event {
+        Event {
+            at,
+            index: 0,
+            output: Output::GateOff {
+                note: Some(note),
+                level: false,
+            },
+        }
+    }
+
+    #[test]
+    fn events_fire_in_time_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(pitch(10)).unwrap();
+        scheduler.push(gate(10, 1, 5, 20, 0)).unwrap();
+        scheduler.push(release(5, 61)).unwrap();
+
+        assert_eq!(scheduler.next(4), None);
+        assert_eq!(scheduler.next(5), Some(release(5, 61)));
+        // same time, in the order they were queued
+        assert_eq!(scheduler.next(10), Some(pitch(10)));
+        assert_eq!(scheduler.next(10), Some(gate(10, 1, 5, 20, 0)));
+        assert_eq!(scheduler.next(14), None);
+        let off = scheduler.next(15).unwrap();
+        assert_eq!((off.at, off.index), (15, 1));
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn gate_is_released_and_repeated() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(gate(0, 0, 5, 20, 2)).unwrap();
+
+        let mut fired = std::vec::Vec::new();
+        for now in 0..100 {
+            while let Some(event) = scheduler.next(now) {
+                let on = matches!(event.output, Output::GateOn { .. });
+                fired.push((event.at, on));
+            }
+        }
+        assert_eq!(
+            fired,
+            [
+                (0, true),
+                (5, false),
+                (20, true),
+                (25, false),
+                (40, true),
+                (45, false)
+            ]
+        );
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn tied_gate_is_not_released() {
+        let mut scheduler = Scheduler::new();
+        scheduler
+            .push(Event {
+                at: 0,
+                index: 0,
+                output: Output::GateOn {
+                    note: 60,
+                    velocity: 100,
+                    legato: false,
+                    level: None,
+                    length: None,
+                    interval: 0,
+                    repeats: 0,
+                },
+            })
+            .unwrap();
+        assert!(scheduler.next(0).is_some());
+        assert_eq!(scheduler.next(u64::MAX), None);
+    }
+
+    #[test]
+    fn releases_are_never_dropped() {
+        let mut scheduler = Scheduler::new();
+        while scheduler.push(gate(10, 0, 5, 5, 1)).is_ok() {}
+        assert_eq!(scheduler.len(), EVENTS_COUNT - RELEASES_COUNT);
+        assert!(scheduler.push(pitch(0)).is_err());
+
+        // the releases of the notes held by ties still fit
+        for note in 0..RELEASES_COUNT as u8 {
+            scheduler.push(release(20, note)).unwrap();
+        }
+        assert_eq!(scheduler.len(), EVENTS_COUNT);
+
+        // every gate opened on a full queue is released, repeats being dropped
+        let (mut on, mut off) = (0, 0);
+        while let Some(event) = scheduler.next(100) {
+            match event.output {
+                Output::GateOn { .. } => on += 1,
+                Output::GateOff { .. } => off += 1,
+                Output::Pitch { .. } => {}
+            }
+        }
+        assert_eq!(off, on + RELEASES_COUNT);
+    }
+
+    #[test]
+    fn fire_writes_the_last_value_of_each_output() {
+        let mut scheduler = Scheduler::new();
+        let mut glides = [Glide::new(); DAC_COUNT];
+        // a gate shorter than the output period is opened and closed at once
+        scheduler.push(gate(0, 0, 0, 0, 0)).unwrap();
+        scheduler.push(gate(0, 2, 5, 0, 0)).unwrap();
+        scheduler
+            .push(Event {
+                at: 0,
+                index: 1,
+                output: Output::Pitch {
+                    value: 1000,
+                    glide: 10,
+                },
+            })
+            .unwrap();
+
+        let mut sent = std::vec::Vec::new();
+        let values = scheduler.fire(0, &mut glides, |message| sent.push(message));
+        assert_eq!(values, [Some(0), Some(0)]);
+        assert_eq!(
+            sent,
+            [
+                Message::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                },
+                Message::NoteOn {
+                    channel: 2,
+                    note: 60,
+                    velocity: 100
+                },
+                Message::NoteOff {
+                    channel: 0,
+                    note: 60,
+                    velocity: 0
+                },
+            ]
+        );
+
+        // the pitch keeps ramping with no event due
+        sent.clear();
+        let values = scheduler.fire(5, &mut glides, |message| sent.push(message));
+        assert_eq!(values, [None, Some(500)]);
+        assert_eq!(
+            sent,
+            [Message::NoteOff {
+                channel: 2,
+                note: 60,
+                velocity: 0
+            }]
+        );
+        assert_eq!(scheduler.fire(10, &mut glides, |_| {}), [None, Some(1000)]);
+        assert_eq!(scheduler.fire(11, &mut glides, |_| {}), [None, None]);
+    }
+
+    // play every track at a tempo, swing, nudge and number of ratchets, the
+    // steps being queued ahead of the grid as by the step task and fired by
+    // the output interrupt, starting at phase microseconds of a millisecond.
+    // Steps follow the internal clock, or an external MIDI clock at the same
+    // tempo received with the resolution of the monotonic timer. Return the
+    // latest an event fired after its time, in microseconds
+    fn play(bpm: u16, swing: u8, nudge: i8, ratchets: u8, phase: u64, external: bool) -> u64 {
+        const STEPS: u64 = 32;
+        let period = 1_000_000 / OUTPUT_RATE_HZ as u64;
+        let mut settings = Settings::new();
+        settings.set_bpm(bpm).set_swing(swing);
+
+        let mut tracks = [Track::new(); TRACKS_COUNT];
+        for (index, track) in tracks.iter_mut().enumerate() {
+            let mut step = Step::new();
+            step.gate = Gate::ON;
+            step.ratchets = ratchets;
+            // nudged early and late, and some notes held by ties
+            step.nudge = if index % 2 == 0 { nudge } else { -nudge };
+            step.tie = index == TRACKS_COUNT - 1;
+            if index % 3 == 0 {
+                track.set_mode(TrackMode::CV);
+            }
+            track.set_pattern([step; STEPS_COUNT]).play();
+        }
+        let mut held = [None; TRACKS_COUNT];
+
+        let mut scheduler = Scheduler::new();
+        let step_length = settings.step_length_ms();
+        let lookahead = settings.lookahead_ms();
+        // the external clock runs before start, which is sent with the pulse
+        // of the first step
+        let mut clock = ClockIn::default();
+        let pulse_time = |pulse: u64| pulse * step_length / MIDI_CLOCKS_PER_STEP as u64;
+        let start = step_length;
+        let mut pulse = 0;
+        let mut grid = step_length;
+        let mut steps = 0;
+        let mut gates = [0; TRACKS_COUNT];
+        let mut late = 0;
+        let mut now = phase;
+        let end = (STEPS + 2) * step_length * 1000;
+        while now < end {
+            let ms = now / 1000;
+            // grid of the steps to prepare and time of the step task
+            let mut prepared = heapless::Vec::<(u64, u64), 2>::new();
+            if external {
+                while pulse_time(pulse) <= ms {
+                    if pulse_time(pulse) == start && !clock.is_running() {
+                        clock.start();
+                    }
+                    if let Some(grid) =
+                        clock.pulse(pulse_time(pulse), MIDI_CLOCKS_PER_STEP, lookahead)
+                    {
+                        let at = grid.saturating_sub(lookahead).max(pulse_time(pulse));
+                        prepared.push((grid, at)).unwrap();
+                    }
+                    pulse += 1;
+                }
+            } else {
+                // the step task runs lookahead before the grid
+                while grid - lookahead <= ms && grid <= STEPS * step_length {
+                    prepared.push((grid, grid - lookahead)).unwrap();
+                    grid += step_length;
+                }
+            }
+
+            for (grid, at) in prepared {
+                if steps == STEPS {
+                    break;
+                }
+                steps += 1;
+                for (index, track) in tracks.iter_mut().enumerate() {
+                    let mut events = Vec::new();
+                    step_events(index, track, &mut held[index], grid, &settings, &mut events);
+                    for event in events {
+                        // only the first step after start is prepared late,
+                        // on its pulse
+                        assert!(event.at >= at || event.at < start, "queued too late");
+                        scheduler.push(event).expect("queue full");
+                    }
+                }
+            }
+
+            while let Some(event) = scheduler.next(ms) {
+                if event.at >= start {
+                    late = late.max(now - event.at * 1000);
+                }
+                if let Output::GateOn { legato: false, .. } = event.output {
+                    gates[event.index] += 1;
+                }
+            }
+            now += period;
+        }
+
+        assert!(scheduler.is_empty());
+        // every gate was played, the tied notes being held through the steps
+        for (index, count) in gates.iter().enumerate() {
+            let expected = if index == TRACKS_COUNT - 1 {
+                1
+            } else {
+                STEPS * ratchets as u64
+            };
+            assert_eq!(*count, expected, "track {}", index + 1);
+        }
+        late
+    }
+
+    #[test]
+    fn events_fire_within_one_output_period() {
+        let period = 1_000_000 / OUTPUT_RATE_HZ as u64;
+        for external in [false, true] {
+            for bpm in [MIN_BPM, 97, BPM, MAX_BPM] {
+                for swing in [0, 33, MAX_SWING] {
+                    for nudge in [-(MAX_NUDGE as i8), -17, 0, MAX_NUDGE as i8] {
+                        for ratchets in [1, 3, MAX_RATCHETS] {
+                            for phase in [0, 1, 500, 999] {
+                                let late = play(bpm, swing, nudge, ratchets, phase, external);
+                                assert!(
+                                    late < period,
+                                    "{}us late at {} BPM, swing {}, nudge {}, {} ratchets{}",
+                                    late,
+                                    bpm,
+                                    swing,
+                                    nudge,
+                                    ratchets,
+                                    if external { ", external clock" } else { "" }
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn audition() {
+        let mut settings = Settings::new();
+        settings.set_bpm(120).set_gate_length(50);
+        let mut scheduler = Scheduler::new();
+        let mut glides = [Glide::new(); DAC_COUNT];
+        let pitch = Pitch::from_note(Note::E, 0);
+        for event in audition_events(1, pitch, 100, &settings) {
+            scheduler.push(event).unwrap();
+        }
+
+        let mut sent = Vec::<Message, 4>::new();
+        let values = scheduler.fire(100, &mut glides, |message| sent.push(message).unwrap());
+        assert_eq!(values, [None, Some(pitch.cv())]);
+        assert_eq!(
+            sent,
+            [Message::NoteOn {
+                channel: 1,
+                note: 64,
+                velocity: 127
+            }]
+        );
+
+        // released after the gate length, the pitch being kept
+        sent.clear();
+        let values = scheduler.fire(349, &mut glides, |message| sent.push(message).unwrap());
+        assert_eq!((values, sent.len()), ([None, None], 0));
+        let values = scheduler.fire(350, &mut glides, |message| sent.push(message).unwrap());
+        assert_eq!(values, [None, None]);
+        assert_eq!(
+            sent,
+            [Message::NoteOff {
+                channel: 1,
+                note: 64,
+                velocity: 0
+            }]
+        );
+        assert!(scheduler.is_empty());
+    }
+}
@@ -1,146 +1,385 @@
+use core::fmt::{self, Write as _};
 use embedded_hal::adc::OneShot;
+use embedded_hal::serial::{Read, Write as _};
 use mcp49xx::Command;
 use rtic::mutex_prelude::*;
-use rtt_target::rprintln;
+use rtt_target::{rprint, rprintln, DownChannel};
 use smart_leds::RGB;
 use systick_monotonic::*;
 
 use crate::app;
+use crate::cc_map::{scale, CcMap, Parameter};
+use crate::console;
 use crate::constants::*;
+use crate::key_events::Gesture;
 use crate::keyboard::*;
+use crate::keymap::{Action, KEYMAP};
 use crate::led::*;
+use crate::midi::{usb_packet_data, Message, Parser, Received, SysexBuffer};
+use crate::pitch::Pitch;
+use crate::remote::{self, Input, RemoteError, Reply, Request, Transport};
+use crate::scheduler::{audition_events, step_events, Event, STEP_EVENTS_COUNT};
+use crate::settings::Settings;
+use crate::sysex::{self, Sysex, SysexError, SYSEX_MAX_SIZE};
 use crate::track::*;
+use crate::usb_midi::{MidiClass, MIDI_PACKET_SIZE};
+use stm32f1xx_hal::pac::Interrupt;
+use stm32f1xx_hal::timer::Event as TimerEvent;
+use stm32f1xx_hal::usb::UsbBusType;
 
 // keyboard key detection controller
 pub(crate) fn keyboard_ctrl(cx: app::keyboard_ctrl::Context) {
-    cx.local.keyboard.read();
-
-    (cx.shared.tracks, cx.shared.current_track).lock(|tracks, current_track| {
-        let track = &mut tracks[*current_track];
-        let mut delay = KEYBOARD_KEY_PRESS_DELAY_MS;
-
-        match (
-            cx.local.keyboard.key_event.function,
-            cx.local.keyboard.key_event.modifier,
-            cx.local.keyboard.key_event.nav,
-            cx.local.keyboard.key_event.code,
-        ) {
-            // switch recording mode
-            (Some(FunctionKey::FN1), Some(ModifierKey::SHIFT), None, None) => {
-                track.toggle_mode();
-                rprintln!(
-                    "Pressed Fn1+Shift, switched track mode to {:?}",
-                    track.get_mode()
-                );
-            }
+    let keyboard = cx.local.keyboard;
+    let now = app::monotonics::now().ticks();
+    keyboard.read(now);
+    // keys are reported once debounced
+    let pressed_at = now.saturating_sub(KEYBOARD_DEBOUNCE_MS);
 
-            // select next track
-            (None, Some(ModifierKey::SHIFT), Some(NavKey::FORWARD), None) => {
-                rprintln!("Pressed Shift+Forward, select next track");
-                *current_track = if *current_track < TRACKS_COUNT - 1 {
-                    *current_track + 1
-                } else {
-                    0
-                };
-            }
+    (
+        cx.shared.tracks,
+        cx.shared.current_track,
+        cx.shared.midi,
+        cx.shared.cc_map,
+        cx.shared.settings,
+        cx.shared.last_step,
+    )
+        .lock(|tracks, current_track, midi, cc_map, settings, last_step| {
+            // combos are matched on the gesture of their last key
+            while let Some(event) = keyboard.next_event() {
+                let track = &mut tracks[*current_track];
+                if let (Key::CodeKey(code), TrackMode::CV) = (event.key, track.get_mode()) {
+                    if !track.is_recording() {
+                        note_entry(track, keyboard, code, event.gesture);
+                    }
+                }
 
-            // play current track
-            (Some(FunctionKey::FN1), None, Some(NavKey::FORWARD), None) => {
-                rprintln!("Pressed Fn1+Forward, toggle play/stop");
-                track.toggle_play();
-            }
+                match (
+                    KEYMAP.action(&keyboard.key_event, event.gesture),
+                    keyboard.key_event.code,
+                ) {
+                    // switch recording mode
+                    (Some(Action::ToggleTrackMode), _) => {
+                        track.toggle_mode();
+                        rprintln!(
+                            "Pressed Fn1+Shift, switched track mode to {:?}",
+                            track.get_mode()
+                        );
+                    }
 
-            // pause current track
-            (Some(FunctionKey::FN1), None, Some(NavKey::BACK), None) => {
-                rprintln!("Pressed Fn1+Back, toggle play/pause");
-                track.toggle_pause();
-            }
+                    // select next track
+                    (Some(Action::NextTrack), _) => {
+                        rprintln!("Pressed Shift+Forward, select next track");
+                        *current_track = if *current_track < TRACKS_COUNT - 1 {
+                            *current_track + 1
+                        } else {
+                            0
+                        };
+                    }
 
-            // clear pattern for current track
-            (Some(FunctionKey::FN2), Some(ModifierKey::SHIFT), None, None) => {
-                rprintln!("Pressed Fn2+Shift, clear pattern");
-                track.clear();
-            }
+                    // play current track
+                    (Some(Action::TogglePlay), _) => {
+                        rprintln!("Pressed Fn1+Forward, toggle play/stop");
+                        track.toggle_play();
+                        midi.send(if track.is_playing() {
+                            Message::Start
+                        } else {
+                            Message::Stop
+                        })
+                        .ok();
+                    }
 
-            // select previous track
-            (None, Some(ModifierKey::SHIFT), Some(NavKey::BACK), None) => {
-                rprintln!("Pressed Shift+Back, select previous track");
-                *current_track = if *current_track > 0 {
-                    *current_track - 1
-                } else {
-                    TRACKS_COUNT - 1
-                };
-            }
+                    // pause current track
+                    (Some(Action::TogglePause), _) => {
+                        rprintln!("Pressed Fn1+Back, toggle play/pause");
+                        track.toggle_pause();
+                        midi.send(if track.is_playing() {
+                            Message::Continue
+                        } else {
+                            Message::Stop
+                        })
+                        .ok();
+                    }
 
-            // switch track
-            (Some(FunctionKey::FN1), None, None, Some(CodeKey)) => {
-                if let Some(code) = cx.local.keyboard.key_event.code {
-                    if let Some(step) = cx.local.keyboard.match_step(code) {
-                        if cx.local.keyboard.key_event.function == Some(FunctionKey::FN1)
-                            && step < TRACKS_COUNT
-                        {
-                            rprintln!("Pressed Fn1+{:?}, switch track", step);
-                            *current_track = step;
-                        }
+                    // clear pattern for current track
+                    (Some(Action::ClearPattern), _) => {
+                        rprintln!("Pressed Fn2+Shift, clear pattern");
+                        track.clear();
+                    }
+
+                    // select previous track
+                    (Some(Action::PreviousTrack), _) => {
+                        rprintln!("Pressed Shift+Back, select previous track");
+                        *current_track = if *current_track > 0 {
+                            *current_track - 1
+                        } else {
+                            TRACKS_COUNT - 1
+                        };
                     }
-                }
-            }
 
-            // randomize gates with probability based on the key pressed
-            (Some(FunctionKey::FN2), None, None, Some(CodeKey)) => {
-                if let Some(code) = cx.local.keyboard.key_event.code {
-                    if let Some(step) = cx.local.keyboard.match_step(code) {
-                        if step <= 8 {
-                            rprintln!("Pressed Fn2+{}, randomize pattern", step);
-                            track.randomize(step as f64 / 8.0);
+                    // switch track
+                    (Some(Action::SelectTrack), Some(code)) => {
+                        if let Some(step) = keyboard.match_step(code) {
+                            if step < TRACKS_COUNT {
+                                rprintln!("Pressed Fn1+{:?}, switch track", step);
+                                *current_track = step;
+                            }
                         }
                     }
-                }
-            }
 
-            // handle key cv/gate
-            (None, None, None, Some(CodeKey)) => {
-                if let Some(code) = cx.local.keyboard.key_event.code {
-                    match track.get_mode() {
-                        TrackMode::CV => {
-                            // handle cv recording mode, for one key pressed
-                            // advance one step forward
-                            if let Some(note) = cx.local.keyboard.match_note(code) {
-                                rprintln!("Pressed note {:?}", note);
-                                track.record_note(note);
+                    // randomize gates with probability based on the key pressed
+                    (Some(Action::Randomize), Some(code)) => {
+                        if let Some(step) = keyboard.match_step(code) {
+                            if step <= 8 {
+                                rprintln!("Pressed Fn2+{}, randomize pattern", step);
+                                track.randomize(step as f64 / 8.0);
                             }
                         }
-                        TrackMode::GATE => {
-                            // handle gate recording mode, toggle gate on/off
-                            rprintln!("Pressed CODE {:?}", code);
-                            if let Some(step) = cx.local.keyboard.match_step(code) {
-                                rprintln!(
-                                    "Got Step {}, track length {:?}",
-                                    step,
-                                    track.get_track_length()
-                                );
-                                if step < track.get_track_length() {
-                                    rprintln!("Pressed Step {}", step);
-                                    track.toggle_step(step);
+                    }
+
+                    // start or stop live recording, the track plays while
+                    // recording
+                    (Some(Action::Record(mode)), _) => {
+                        let mode = if track.get_record_mode() == Some(mode) {
+                            None
+                        } else {
+                            track.play();
+                            Some(mode)
+                        };
+                        rprintln!("Double tapped Fn, live recording {:?}", mode);
+                        track.set_record_mode(mode);
+                    }
+
+                    // play the steps with a fill condition on every track
+                    (Some(Action::ToggleFill), _) => {
+                        let fill = !track.get_fill();
+                        rprintln!("Double tapped Shift, fill {}", fill);
+                        for track in tracks.iter_mut() {
+                            track.set_fill(fill);
+                        }
+                    }
+
+                    // record live on the step nearest to the playhead
+                    (Some(Action::StepInput), Some(code)) if track.is_recording() => {
+                        let late = pressed_at.saturating_sub(last_step.ticks()) * 2
+                            >= settings.step_length_ms();
+                        let step = track.nearest_step(late);
+                        let pitch = match track.get_mode() {
+                            TrackMode::CV => keyboard.match_pitch(code),
+                            TrackMode::GATE | TrackMode::VELOCITY => None,
+                        };
+                        rprintln!("Recorded step {} {:?}", step, pitch);
+                        track.record_step(step, pitch);
+
+                        // the step being played is already past, play the note
+                        // now
+                        if let (Some(pitch), false) = (pitch, late) {
+                            app::audition::spawn(*current_track, pitch).ok();
+                        }
+                    }
+
+                    // handle key cv/gate
+                    (Some(Action::StepInput), Some(code)) => {
+                        match track.get_mode() {
+                            TrackMode::CV => {
+                                // play the note entered while the track is stopped
+                                if !track.is_playing() {
+                                    if let Some(pitch) = keyboard.match_pitch(code) {
+                                        app::audition::spawn(*current_track, pitch).ok();
+                                    }
+                                }
+
+                                // handle cv recording mode, a step key is held
+                                // to edit its note, keys pressed together are
+                                // recorded as a chord
+                                if let Some((_, step)) = keyboard.entry.editing {
+                                    if let Some(pitch) = keyboard.match_pitch(code) {
+                                        rprintln!("Pressed note {}, set step {}", pitch, step);
+                                        track.set_note(step, pitch);
+                                    }
+                                } else if keyboard.key_event.codes.len() > 1 {
+                                    record_chord(track, keyboard);
+                                } else {
+                                    keyboard.entry.pending = Some(code);
+                                }
+                            }
+                            TrackMode::GATE | TrackMode::VELOCITY => {
+                                // handle gate recording mode, toggle gate on/off
+                                rprintln!("Pressed CODE {:?}", code);
+                                // holding a step key and pressing a level key
+                                // sets the velocity of the step
+                                let editing = keyboard
+                                    .key_event
+                                    .codes
+                                    .iter()
+                                    .find(|held| *held != code)
+                                    .and_then(|held| keyboard.match_step(held))
+                                    .filter(|step| *step < track.get_track_length());
+                                if let (Some(step), Some(velocity)) =
+                                    (editing, KEYMAP.velocity(code))
+                                {
+                                    rprintln!("Set step {} velocity {}", step, velocity);
+                                    track.set_velocity(step, velocity);
+                                } else if let Some(step) = keyboard.match_step(code) {
+                                    rprintln!(
+                                        "Got Step {}, track length {:?}",
+                                        step,
+                                        track.get_track_length()
+                                    );
+                                    // holding a step key and pressing another one
+                                    // selects the steps between both keys
+                                    let held = keyboard
+                                        .key_event
+                                        .codes
+                                        .iter()
+                                        .find(|held| *held != code)
+                                        .and_then(|held| keyboard.match_step(held));
+                                    match held {
+                                        Some(first) => set_step_range(track, first, step),
+                                        None if step < track.get_track_length() => {
+                                            rprintln!("Pressed Step {}", step);
+                                            track.toggle_step(step);
+                                        }
+                                        None => {}
+                                    }
                                 }
                             }
                         }
                     }
+
+                    // change the octave of the notes entered
+                    (Some(Action::OctaveUp), _) => {
+                        let octave = keyboard.get_octave() + 1;
+                        keyboard.set_octave(octave);
+                        rprintln!("Pressed Forward, octave {}", keyboard.get_octave());
+                    }
+                    (Some(Action::OctaveDown), _) => {
+                        let octave = keyboard.get_octave() - 1;
+                        keyboard.set_octave(octave);
+                        rprintln!("Pressed Back, octave {}", keyboard.get_octave());
+                    }
+
+                    // send current track as a SysEx dump
+                    (Some(Action::DumpTrack), _) => {
+                        rprintln!("Pressed Fn2+Back, dump track {}", *current_track);
+                        let mut sysex = [0; SYSEX_MAX_SIZE];
+                        let len = sysex::encode_track(*current_track, track, &mut sysex);
+                        midi.send_sysex(&sysex[..len]).ok();
+                    }
+
+                    // send all tracks as a SysEx dump
+                    (Some(Action::DumpProject), _) => {
+                        rprintln!("Pressed Fn2+Forward, dump project");
+                        let mut sysex = [0; SYSEX_MAX_SIZE];
+                        let len = sysex::encode_project(tracks, &mut sysex);
+                        midi.send_sysex(&sysex[..len]).ok();
+                    }
+
+                    (_, _) => {}
                 }
             }
 
-            (_, _, _, _) => {
-                delay = KEYBOARD_REFRESH_MS;
+            // learn MIDI CC for the parameter assigned to the step key while
+            // it is held
+            let learning = match (
+                KEYMAP.action(&keyboard.key_event, Gesture::Pressed),
+                keyboard.key_event.code,
+            ) {
+                (Some(Action::LearnCc), Some(code)) => {
+                    keyboard.match_step(code).and_then(Parameter::from_index)
+                }
+                (_, _) => None,
+            };
+            if learning.is_some() && cc_map.get_learning() != learning {
+                rprintln!(
+                    "Pressed Shift+{:?}, learn MIDI CC for {:?}",
+                    keyboard.key_event.code,
+                    learning
+                );
+            }
+            match learning {
+                Some(parameter) => cc_map.learn(parameter),
+                None => cc_map.stop_learning(),
+            };
+        });
+
+    app::keyboard_ctrl::spawn_after(systick_monotonic::ExtU64::millis(KEYBOARD_REFRESH_MS))
+        .unwrap();
+}
+
+// note entry in CV mode: a key pressed alone records its note at the cursor
+// when released, unless it is held long enough to select its step for editing
+fn note_entry(track: &mut Track, keyboard: &mut Keyboard, code: CodeKey, gesture: Gesture) {
+    match gesture {
+        Gesture::Held if keyboard.entry.pending == Some(code) => {
+            keyboard.entry.pending = None;
+            if let Some(step) = keyboard.match_step(code) {
+                if step < track.get_track_length() {
+                    rprintln!("Held Step {}, edit its note", step);
+                    keyboard.entry.editing = Some((code, step));
+                }
             }
         }
-        app::keyboard_ctrl::spawn_after(systick_monotonic::ExtU64::millis(delay)).unwrap();
-    });
+        Gesture::Released => {
+            if keyboard.entry.pending == Some(code) {
+                keyboard.entry.pending = None;
+                if let Some(pitch) = keyboard.match_pitch(code) {
+                    rprintln!("Pressed note {}", pitch);
+                    track.record_note(pitch);
+                }
+            }
+            if matches!(keyboard.entry.editing, Some((held, _)) if held == code) {
+                keyboard.entry.editing = None;
+            }
+            if keyboard.key_event.codes.is_empty() {
+                keyboard.entry.chord_recorded = 0;
+            }
+        }
+        _ => {}
+    }
 }
 
-// led_ctrl handle led display
-pub(crate) fn led_ctrl(cx: app::led_ctrl::Context) {
+// record the notes held together on consecutive steps, from the lowest to
+// the highest. The notes of the chord recorded before the last one was pressed
+// are written again in pitch order
+fn record_chord(track: &mut Track, keyboard: &mut Keyboard) {
+    let mut pitches: heapless::Vec<Pitch, 13> = keyboard
+        .key_event
+        .codes
+        .iter()
+        .filter_map(|code| keyboard.match_pitch(code))
+        .collect();
+    pitches.sort_unstable();
+    rprintln!("Pressed chord {:?}", pitches);
+
+    let length = track.get_track_length();
+    let recorded = keyboard.entry.chord_recorded % length;
+    let start = (track.get_cursor() + length - recorded) % length;
+    track.set_cursor(start);
+    keyboard.entry.pending = None;
+    keyboard.entry.chord_recorded = pitches.len();
+    for pitch in pitches {
+        track.record_note(pitch);
+    }
+}
+
+// set the gates of the steps between two step keys, both included, to the
+// gate of the first one
+fn set_step_range(track: &mut Track, first: usize, last: usize) {
+    let length = track.get_track_length();
+    if first >= length {
+        return;
+    }
+    let gate = track.get_gate(first);
+    rprintln!("Pressed Steps {} to {}, set gates {:?}", first, last, gate);
+    for step in first.min(last)..=first.max(last).min(length - 1) {
+        track.set_gate(step, gate);
+    }
+}
+
+// led_ctrl handle led display, the frame being sent once tracks are released
+pub(crate) fn led_ctrl(mut cx: app::led_ctrl::Context) {
     (
-        cx.shared.led_driver,
+        &mut cx.shared.led_driver,
         cx.shared.tracks,
         cx.shared.current_track,
     )
@@ -148,31 +387,545 @@ pub(crate) fn led_ctrl(cx: app::led_ctrl::Context) {
             let track = &mut tracks[*current_track];
             match track.get_mode() {
                 TrackMode::CV => cv_recording(led_driver, track, *current_track),
-                TrackMode::GATE => gate_recording(led_driver, track, *current_track),
+                TrackMode::GATE | TrackMode::VELOCITY => {
+                    gate_recording(led_driver, track, *current_track)
+                }
             }
         });
+    cx.shared.led_driver.lock(|led_driver| led_driver.write());
 
     app::led_ctrl::spawn_after(systick_monotonic::ExtU64::millis(LED_REFRESH_MS)).unwrap();
 }
 
-// tick move play cursor ahead by 1 step on each track
-pub(crate) fn tick(cx: app::tick::Context, instant: fugit::TimerInstantU64<100>) {
-    (cx.shared.tracks, cx.shared.current_track).lock(|tracks, current_track| {
-        // move 1 step forward on each tracks
-        for i in 0..TRACKS_COUNT {
-            tracks[i].tick();
-        }
-        rprintln!("CURRENT TRACK [{:?}]", *current_track,);
+// tick is the internal clock, it prepares the next step ahead of the grid
+// unless the sequencer follows an external MIDI clock
+pub(crate) fn tick(mut cx: app::tick::Context, instant: fugit::TimerInstantU64<1000>) {
+    let (step_length, lookahead) = cx.shared.settings.lock(|settings| {
+        (
+            settings.step_length_ms().millis(),
+            settings.lookahead_ms().millis(),
+        )
     });
+    let next_instant = instant + step_length;
 
-    app::cv_ctrl::spawn().unwrap();
-    app::gate_reset::spawn_at(instant + *cx.local.gate_length).unwrap();
+    if !cx.shared.clock_in.lock(|clock_in| clock_in.is_running()) {
+        app::step::spawn_at(next_instant - lookahead, next_instant).unwrap();
+        app::clock_out::spawn(instant, step_length, 0).unwrap();
+    }
 
     // call next tick
-    let next_instant = instant + *cx.local.step_length;
     app::tick::spawn_at(next_instant, next_instant).unwrap();
 }
 
+// step move play cursor ahead by 1 step on each track, instant being the
+// time of the step on the grid. The outputs of the step are queued for the
+// output interrupt at their offset from the grid, given by swing and nudge
+pub(crate) fn step(mut cx: app::step::Context, instant: fugit::TimerInstantU64<1000>) {
+    // MIDI notes held through the next step by tied steps
+    let held = cx.local.held;
+    let mut cursors = [0; TRACKS_COUNT];
+    let mut events: heapless::Vec<Event, STEP_EVENTS_COUNT> = heapless::Vec::new();
+    cx.shared.last_step.lock(|last_step| *last_step = instant);
+    let settings = cx.shared.settings.lock(|settings| *settings);
+
+    cx.shared.tracks.lock(|tracks| {
+        // move 1 step forward on each tracks
+        for (i, track) in tracks.iter_mut().enumerate() {
+            step_events(
+                i,
+                track,
+                &mut held[i],
+                instant.ticks(),
+                &settings,
+                &mut events,
+            );
+        }
+        for (cursor, track) in cursors.iter_mut().zip(tracks.iter_mut()) {
+            *cursor = track.get_cursor();
+        }
+    });
+
+    cx.shared.scheduler.lock(|scheduler| {
+        for event in events {
+            if scheduler.push(event).is_err() {
+                rprintln!("Output queue full, event dropped");
+            }
+        }
+    });
+
+    // report the playhead position to subscribed remote hosts
+    if cx.shared.remote_subscribed.lock(|subscribed| *subscribed) {
+        cx.shared
+            .console_out
+            .lock(|out| write_reply(out, Reply::Playhead(cursors)));
+        rtic::pend(Interrupt::USART1);
+    }
+}
+
+// output_ctrl fire the queued outputs once due, then ramp the pitch CV of
+// the gliding outputs. MIDI messages and DAC values are sent from midi_out and
+// dac_out, out of the interrupt
+pub(crate) fn output_ctrl(mut cx: app::output_ctrl::Context) {
+    cx.local.output_timer.clear_interrupt(TimerEvent::Update);
+    let now = app::monotonics::now().ticks();
+    let glides = cx.local.glides;
+
+    let values = cx.shared.scheduler.lock(|scheduler| {
+        scheduler.fire(now, glides, |message| {
+            app::midi_out::spawn(message).ok();
+        })
+    });
+
+    // the latest value of each output is kept until written
+    if values.iter().any(Option::is_some) {
+        cx.shared.dac_values.lock(|dac_values| {
+            for (pending, value) in dac_values.iter_mut().zip(values) {
+                if value.is_some() {
+                    *pending = value;
+                }
+            }
+        });
+        app::dac_out::spawn().ok();
+    }
+}
+
+// dac_out write the values of the DAC outputs fired by the output interrupt
+pub(crate) fn dac_out(mut cx: app::dac_out::Context) {
+    let cmd = Command::default();
+    let values = cx
+        .shared
+        .dac_values
+        .lock(|dac_values| core::mem::replace(dac_values, [None; DAC_COUNT]));
+
+    (cx.shared.dac1, cx.shared.dac2, cx.shared.spi_dac).lock(|dac1, dac2, spi_dac| {
+        let results = [
+            values[0].map(|value| dac1.send(spi_dac, cmd.value(value))),
+            values[1].map(|value| dac2.send(spi_dac, cmd.value(value))),
+        ];
+        for error in results.into_iter().flatten().filter_map(Result::err) {
+            rprintln!("DAC write failed: {:?}", error);
+        }
+    });
+}
+
+// midi_out send a MIDI message fired by the output interrupt
+pub(crate) fn midi_out(mut cx: app::midi_out::Context, message: Message) {
+    cx.shared.midi.lock(|midi| midi.send(message).ok());
+}
+
+// clock_out send MIDI clock pulses evenly spread over a step
+pub(crate) fn clock_out(
+    mut cx: app::clock_out::Context,
+    instant: fugit::TimerInstantU64<1000>,
+    step_length: fugit::Duration<u64, 1, 1000>,
+    pulse: u8,
+) {
+    cx.shared
+        .midi
+        .lock(|midi| midi.send(Message::TimingClock).ok());
+
+    let next_pulse = pulse + 1;
+    if next_pulse < MIDI_CLOCKS_PER_STEP {
+        let next_instant = instant + step_length * next_pulse as u32 / MIDI_CLOCKS_PER_STEP as u32;
+        app::clock_out::spawn_at(next_instant, instant, step_length, next_pulse).unwrap();
+    }
+}
+
+// midi_in handle clock, transport and control change messages
+pub(crate) fn midi_in(cx: app::midi_in::Context, message: Message) {
+    (
+        cx.shared.tracks,
+        cx.shared.current_track,
+        cx.shared.clock_in,
+        cx.shared.cc_map,
+        cx.shared.settings,
+    )
+        .lock(
+            |tracks, current_track, clock_in, cc_map, settings| match message {
+                Message::TimingClock => {
+                    let now = app::monotonics::now().ticks();
+                    let lookahead = settings.lookahead_ms();
+                    if let Some(grid) = clock_in.pulse(now, MIDI_CLOCKS_PER_STEP, lookahead) {
+                        // prepared lookahead before the grid, like on the
+                        // internal clock, or right away when it is too late
+                        let at = grid.saturating_sub(lookahead).max(now);
+                        app::step::spawn_at(
+                            fugit::TimerInstantU64::from_ticks(at),
+                            fugit::TimerInstantU64::from_ticks(grid),
+                        )
+                        .ok();
+                    }
+                }
+                Message::Start => {
+                    rprintln!("MIDI start");
+                    clock_in.start();
+                    for track in tracks.iter_mut() {
+                        track.rewind().play();
+                    }
+                }
+                Message::Continue => {
+                    rprintln!("MIDI continue");
+                    clock_in.resume();
+                    for track in tracks.iter_mut() {
+                        track.play();
+                    }
+                }
+                Message::Stop => {
+                    rprintln!("MIDI stop");
+                    clock_in.stop();
+                    for track in tracks.iter_mut() {
+                        track.stop();
+                    }
+                }
+                Message::ControlChange { control, value, .. } => {
+                    if let Some(parameter) = cc_map.get_learning() {
+                        if cc_map.assign(parameter, control) {
+                            rprintln!("Assigned MIDI CC {} to {:?}", control, parameter);
+                            app::save_cc_map::spawn().ok();
+                        }
+                    }
+                    if let Some(parameter) = cc_map.find(control) {
+                        set_parameter(parameter, value, tracks, current_track, settings);
+                    }
+                }
+                _ => {}
+            },
+        );
+}
+
+// set a sequencer parameter from a MIDI CC value
+fn set_parameter(
+    parameter: Parameter,
+    value: u8,
+    tracks: &mut [Track; TRACKS_COUNT],
+    current_track: &mut usize,
+    settings: &mut Settings,
+) {
+    let track = &mut tracks[*current_track];
+    match parameter {
+        Parameter::Tempo => {
+            settings.set_bpm(scale(value, MIN_BPM as i32, MAX_BPM as i32) as u16);
+        }
+        Parameter::GateLength => {
+            settings.set_gate_length(scale(value, 1, 100) as u8);
+        }
+        Parameter::TrackLength => {
+            track.set_track_length(scale(value, 1, STEPS_COUNT as i32) as usize);
+        }
+        Parameter::Transpose => {
+            track.set_transpose(scale(value, -(MAX_TRANSPOSE as i32), MAX_TRANSPOSE as i32) as i8);
+        }
+        Parameter::RandomDensity => {
+            track.randomize(value as f64 / 127.0);
+        }
+        Parameter::CurrentTrack => {
+            *current_track = scale(value, 0, TRACKS_COUNT as i32 - 1) as usize;
+        }
+    }
+}
+
+// usart_midi parse MIDI bytes received on the serial MIDI input
+pub(crate) fn usart_midi(cx: app::usart_midi::Context) {
+    while let Ok(byte) = cx.local.midi_rx.read() {
+        receive(cx.local.midi_parser, byte);
+    }
+}
+
+// save_cc_map persist the MIDI CC mapping after it has been learned. Erasing
+// flash stalls the CPU for tens of milliseconds, freezing the outputs and the
+// clock, so the mapping is only saved once the transport is stopped
+pub(crate) fn save_cc_map(mut cx: app::save_cc_map::Context) {
+    let playing = cx.shared.clock_in.lock(|clock_in| clock_in.is_running())
+        || cx
+            .shared
+            .tracks
+            .lock(|tracks| tracks.iter_mut().any(|track| track.is_playing()));
+    if playing {
+        app::save_cc_map::spawn_after(STORAGE_RETRY_MS.millis()).ok();
+        return;
+    }
+
+    (cx.shared.cc_map, cx.shared.storage).lock(|cc_map, storage| {
+        if storage.save_cc_map(cc_map).is_err() {
+            rprintln!("Failed to save MIDI CC map");
+        }
+    });
+}
+
+// sysex_in load received dumps and answer dump requests
+pub(crate) fn sysex_in(cx: app::sysex_in::Context, sysex: SysexBuffer) {
+    (cx.shared.tracks, cx.shared.midi).lock(|tracks, midi| {
+        let mut reply = [0; SYSEX_MAX_SIZE];
+        let len = match sysex::decode(&sysex, tracks) {
+            Ok(Sysex::TrackLoaded(index)) => {
+                rprintln!("Loaded track {} from SysEx dump", index);
+                return;
+            }
+            Ok(Sysex::ProjectLoaded) => {
+                rprintln!("Loaded project from SysEx dump");
+                return;
+            }
+            Ok(Sysex::TrackRequest(index)) => {
+                sysex::encode_track(index, &tracks[index], &mut reply)
+            }
+            Ok(Sysex::ProjectRequest) => sysex::encode_project(tracks, &mut reply),
+            Err(SysexError::Ignored) => return,
+            Err(error) => {
+                rprintln!("Rejected SysEx message: {:?}", error);
+                sysex::encode_nak(error, &mut reply)
+            }
+        };
+        midi.send_sysex(&reply[..len]).ok();
+    });
+}
+
+// serial console, receive command lines and remote frames, and send queued
+// replies
+pub(crate) fn usart_console(mut cx: app::usart_console::Context) {
+    while let Ok(byte) = cx.local.console_rx.read() {
+        let byte = match cx.local.remote_frame.push(byte) {
+            Some(Input::Text(byte)) => byte,
+            Some(Input::Frame(Ok(payload))) => {
+                if app::remote_cmd::spawn(payload).is_err() {
+                    cx.shared.console_out.lock(|out| {
+                        write_reply(out, Reply::Nak(0, RemoteError::Busy));
+                    });
+                }
+                continue;
+            }
+            Some(Input::Frame(Err(error))) => {
+                cx.shared
+                    .console_out
+                    .lock(|out| write_reply(out, Reply::Nak(0, error)));
+                continue;
+            }
+            None => continue,
+        };
+        match cx.local.console_line.push(byte) {
+            Some(Ok(line)) => {
+                if app::console_cmd::spawn(line).is_err() {
+                    cx.shared
+                        .console_out
+                        .lock(|out| writeln!(out, "error: busy").ok());
+                }
+            }
+            Some(Err(error)) => {
+                cx.shared
+                    .console_out
+                    .lock(|out| writeln!(out, "error: {}", error).ok());
+            }
+            None => {}
+        }
+    }
+
+    let tx = cx.local.console_tx;
+    cx.shared.console_out.lock(|out| {
+        while let Some(byte) = out.peek() {
+            if tx.write(byte).is_err() {
+                break;
+            }
+            out.pop();
+        }
+        // wait for the transmit register to be empty to send the rest
+        if out.is_empty() {
+            tx.unlisten();
+        } else {
+            tx.listen();
+        }
+    });
+}
+
+// execute a console command line
+pub(crate) fn console_cmd(cx: app::console_cmd::Context, line: console::Line) {
+    (
+        cx.shared.tracks,
+        cx.shared.current_track,
+        cx.shared.settings,
+        cx.shared.dump_loader,
+        cx.shared.console_out,
+    )
+        .lock(|tracks, current_track, settings, loader, out| {
+            console::execute_queued(&line, tracks, current_track, settings, loader, out);
+        });
+    rtic::pend(Interrupt::USART1);
+}
+
+// execute a remote control request
+pub(crate) fn remote_cmd(cx: app::remote_cmd::Context, payload: remote::Payload) {
+    (
+        cx.shared.tracks,
+        cx.shared.current_track,
+        cx.shared.settings,
+        cx.shared.remote_subscribed,
+        cx.shared.console_out,
+    )
+        .lock(|tracks, current_track, settings, subscribed, out| {
+            let command = payload.first().copied().unwrap_or(0);
+            let reply = Request::decode(&payload)
+                .and_then(|request| {
+                    run_request(
+                        request,
+                        command,
+                        tracks,
+                        current_track,
+                        settings,
+                        subscribed,
+                    )
+                })
+                .unwrap_or_else(|error| Reply::Nak(command, error));
+            write_reply(out, reply);
+        });
+    rtic::pend(Interrupt::USART1);
+}
+
+fn run_request(
+    request: Request,
+    command: u8,
+    tracks: &mut [Track; TRACKS_COUNT],
+    current_track: &mut usize,
+    settings: &mut Settings,
+    subscribed: &mut bool,
+) -> Result<Reply, RemoteError> {
+    match request {
+        Request::GetStep { track, step } => {
+            return Ok(Reply::Step(track, step, tracks[track].get_pattern()[step]));
+        }
+        Request::SetStep { track, step, value } => {
+            tracks[track].set_step(step, value);
+        }
+        Request::GetTrack(track) => return Ok(Reply::Track(track, tracks[track].to_bytes())),
+        Request::SetTrack(track, bytes) => {
+            tracks[track]
+                .load_bytes(bytes)
+                .ok_or(RemoteError::InvalidArgument)?;
+        }
+        Request::GetSettings => {
+            return Ok(Reply::Settings {
+                bpm: settings.get_bpm(),
+                gate_length: settings.get_gate_length(),
+                current_track: *current_track,
+            });
+        }
+        Request::SetSettings {
+            bpm,
+            gate_length,
+            current_track: track,
+        } => {
+            settings.set_bpm(bpm).set_gate_length(gate_length);
+            *current_track = track;
+        }
+        Request::Subscribe(enabled) => *subscribed = enabled,
+        Request::Transport(transport) => {
+            for track in tracks.iter_mut() {
+                match transport {
+                    Transport::Stop => track.stop(),
+                    Transport::Start => track.rewind().play(),
+                    Transport::Continue => track.play(),
+                };
+            }
+        }
+    }
+    Ok(Reply::Ack(command))
+}
+
+// queue a remote reply frame, frames which do not fit are dropped
+fn write_reply(out: &mut console::Output, reply: Reply) {
+    let mut frame = [0; remote::FRAME_SIZE];
+    let len = reply.encode(&mut frame);
+    out.write_bytes(&frame[..len]);
+}
+
+// RTT command channel, accepting the same commands as the serial console.
+// The channel is polled as the probe does not raise any interrupt
+pub(crate) fn rtt_poll(mut cx: app::rtt_poll::Context) {
+    let mut buf = [0; 16];
+    loop {
+        let len = cx.local.rtt_down.read(&mut buf);
+        if len == 0 {
+            break;
+        }
+        for byte in &buf[..len] {
+            match cx.local.rtt_line.push(*byte) {
+                Some(Ok(line)) => {
+                    (
+                        &mut cx.shared.tracks,
+                        &mut cx.shared.current_track,
+                        &mut cx.shared.settings,
+                        &mut cx.shared.dump_loader,
+                    )
+                        .lock(|tracks, current_track, settings, loader| {
+                            console::execute(
+                                &line,
+                                tracks,
+                                current_track,
+                                settings,
+                                loader,
+                                &mut RttOutput,
+                            )
+                            .ok();
+                        });
+                }
+                Some(Err(error)) => rprintln!("error: {}", error),
+                None => {}
+            }
+        }
+    }
+    app::rtt_poll::spawn_after(RTT_POLL_MS.millis()).ok();
+}
+
+// console replies sent over the RTT up channel
+struct RttOutput;
+
+impl fmt::Write for RttOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        rprint!("{}", s);
+        Ok(())
+    }
+}
+
+pub(crate) fn usb_tx(cx: app::usb_tx::Context) {
+    (cx.shared.usb_dev, cx.shared.midi, cx.shared.usb_parser).lock(usb_poll);
+}
+
+pub(crate) fn usb_rx0(cx: app::usb_rx0::Context) {
+    (cx.shared.usb_dev, cx.shared.midi, cx.shared.usb_parser).lock(usb_poll);
+}
+
+// poll the USB device and forward received MIDI messages
+fn usb_poll(
+    usb_dev: &mut usb_device::device::UsbDevice<'static, UsbBusType>,
+    midi: &mut MidiClass<'static, UsbBusType>,
+    parser: &mut Parser,
+) {
+    if !usb_dev.poll(&mut [midi]) {
+        return;
+    }
+
+    let mut buf = [0u8; MIDI_PACKET_SIZE];
+    if let Ok(count) = midi.read(&mut buf) {
+        for packet in buf[..count].chunks_exact(4) {
+            for byte in usb_packet_data(packet) {
+                receive(parser, *byte);
+            }
+        }
+    }
+}
+
+// feed a byte received on a MIDI input and dispatch complete messages
+fn receive(parser: &mut Parser, byte: u8) {
+    match parser.push(byte) {
+        Some(Received::Message(message)) => {
+            app::midi_in::spawn(message).ok();
+        }
+        Some(Received::Sysex(sysex)) => {
+            if let Ok(sysex) = SysexBuffer::from_slice(sysex) {
+                app::sysex_in::spawn(sysex).ok();
+            }
+        }
+        None => {}
+    }
+}
+
 // cv_recording define led lighting when note recording mode is on
 fn cv_recording(led_driver: &mut LedDriver, track: &mut Track, current_track: usize) {
     led_driver.clear();
@@ -190,107 +943,38 @@ fn cv_recording(led_driver: &mut LedDriver, track: &mut Track, current_track: us
     led_driver
         .set_active_track(current_track)
         .set_track_mode(track.get_mode())
-        .set_clock(track.get_cursor())
-        .write();
+        .set_clock(track.get_cursor());
 }
 
 // gate_recording define led lighting when step recording mode is on
 fn gate_recording(led_driver: &mut LedDriver, track: &mut Track, current_track: usize) {
     led_driver.clear();
 
-    // display current active gate
-    for step in 0..track.get_track_length() {
-        if track.get_gate(step) == Gate::ON {
-            led_driver.set_gate_on(step);
+    // display current active gate, brighter for louder steps
+    let pattern = track.get_pattern();
+    for (step, step_data) in pattern.iter().enumerate().take(track.get_track_length()) {
+        if step_data.gate == Gate::ON {
+            led_driver.set_gate_on(step, step_data.velocity);
         }
     }
 
     led_driver
         .set_active_track(current_track)
         .set_track_mode(track.get_mode())
-        .set_clock(track.get_cursor())
-        .write();
-}
-
-// write Gate/CV value for each DAC
-pub(crate) fn cv_ctrl(cx: app::cv_ctrl::Context) {
-    let cmd = Command::default();
-
-    (
-        cx.shared.dac1,
-        cx.shared.dac2,
-        cx.shared.spi_dac,
-        cx.shared.tracks,
-    )
-        .lock(|dac1, dac2, spi_dac, tracks| {
-            dac1.send(
-                spi_dac,
-                cmd.value(match tracks[0].get_mode() {
-                    TrackMode::GATE => {
-                        if tracks[0].get_current_gate() == Gate::ON {
-                            4080
-                        } else {
-                            0
-                        }
-                    }
-                    TrackMode::CV => match_note_to_cv(tracks[0].get_current_note()),
-                }),
-            )
-            .unwrap();
-
-            dac2.send(
-                spi_dac,
-                cmd.value(match tracks[1].get_mode() {
-                    TrackMode::GATE => {
-                        if tracks[1].get_current_gate() == Gate::ON {
-                            4080
-                        } else {
-                            0
-                        }
-                    }
-                    TrackMode::CV => match_note_to_cv(tracks[1].get_current_note()),
-                }),
-            )
-            .unwrap();
-        });
+        .set_clock(track.get_cursor());
 }
 
-// reset all gate after trigger
-pub(crate) fn gate_reset(cx: app::gate_reset::Context) {
-    let cmd = Command::default();
-
-    (
-        cx.shared.dac1,
-        cx.shared.dac2,
-        cx.shared.spi_dac,
-        cx.shared.tracks,
-    )
-        .lock(|dac1, dac2, spi_dac, tracks| {
-            if tracks[0].get_mode() == TrackMode::GATE {
-                dac1.send(spi_dac, cmd.value(0)).unwrap();
-            }
+// audition queue a note entered on the keyboard on the pitch output and MIDI
+// channel of its track, for the gate length
+pub(crate) fn audition(mut cx: app::audition::Context, index: usize, pitch: Pitch) {
+    let now = app::monotonics::now().ticks();
+    let settings = cx.shared.settings.lock(|settings| *settings);
 
-            if tracks[1].get_mode() == TrackMode::GATE {
-                dac2.send(spi_dac, cmd.value(0)).unwrap();
+    cx.shared.scheduler.lock(|scheduler| {
+        for event in audition_events(index, pitch, now, &settings) {
+            if scheduler.push(event).is_err() {
+                rprintln!("Output queue full, event dropped");
             }
-        });
-}
-
-// return led position based on a given note
-fn match_note_to_cv(note: Note) -> u16 {
-    // TODO: adjust with correct CV value
-    match note {
-        Note::C => 0,
-        Note::D => 400,
-        Note::E => 800,
-        Note::F => 1200,
-        Note::G => 1600,
-        Note::A => 2400,
-        Note::B => 2800,
-        Note::Db => 3200,
-        Note::Eb => 3600,
-        Note::Gb => 4080,
-        Note::Ab => 4080,
-        Note::Bb => 4080,
-    }
+        }
+    });
 }
@@ -0,0 +1,99 @@
+use crate::constants::*;
+
+// global sequencer settings
+#[derive(Copy, Clone, Debug)]
+pub struct Settings {
+    bpm: u16,
+    gate_length: u8,
+    // delay of the even steps in percent of the step length
+    swing: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        Settings {
+            bpm: BPM,
+            gate_length: GATE_LENGTH,
+            swing: SWING,
+        }
+    }
+
+    pub fn get_bpm(&self) -> u16 {
+        self.bpm
+    }
+
+    pub fn set_bpm(&mut self, bpm: u16) -> &mut Self {
+        self.bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+        self
+    }
+
+    // gate length as a percentage of the step length
+    pub fn get_gate_length(&self) -> u8 {
+        self.gate_length
+    }
+
+    pub fn set_gate_length(&mut self, gate_length: u8) -> &mut Self {
+        self.gate_length = gate_length.clamp(1, 100);
+        self
+    }
+
+    pub fn get_swing(&self) -> u8 {
+        self.swing
+    }
+
+    pub fn set_swing(&mut self, swing: u8) -> &mut Self {
+        self.swing = swing.min(MAX_SWING);
+        self
+    }
+
+    pub fn step_length_ms(&self) -> u64 {
+        60_000 / self.bpm as u64
+    }
+
+    pub fn gate_length_ms(&self) -> u64 {
+        self.step_length_ms() * self.gate_length as u64 / 100
+    }
+
+    // delay of an even step for a swing amount in percent
+    pub fn swing_ms(&self, swing: u8) -> u64 {
+        self.step_length_ms() * swing as u64 / 100
+    }
+
+    // offset of a nudged step from the grid, negative when played early
+    pub fn nudge_ms(&self, nudge: i8) -> i64 {
+        self.step_length_ms() as i64 * nudge as i64 / 100
+    }
+
+    // offset of a step from the grid, swing and nudge being limited to the
+    // lookahead
+    pub fn offset_ms(&self, swing: u8, nudge: i8) -> i64 {
+        let max = self.lookahead_ms() as i64;
+        (self.swing_ms(swing) as i64 + self.nudge_ms(nudge)).clamp(-max, max)
+    }
+
+    // time steps are prepared ahead of the grid, so that they can be nudged
+    // early
+    pub fn lookahead_ms(&self) -> u64 {
+        self.step_length_ms() * MAX_NUDGE as u64 / 100
+    }
+
+    // interval between the gates of a step lasting length milliseconds and
+    // played a number of times
+    pub fn ratchet_ms(&self, ratchets: u8, length: u64) -> u64 {
+        length / ratchets.max(1) as u64
+    }
+
+    // length of a gate in percent of the interval between the gates of a
+    // step, the gate is closed before the next one so that it is triggered
+    // again
+    pub fn gate_ms(&self, percent: u8, ratchets: u8, length: u64) -> u64 {
+        let interval = self.ratchet_ms(ratchets, length);
+        (interval * percent as u64 / 100).min(interval.saturating_sub(1))
+    }
+}
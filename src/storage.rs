@@ -0,0 +1,40 @@
+use stm32f1xx_hal::flash::{self, FlashSize, SectorSize};
+
+use crate::cc_map::{CcMap, PARAMETERS_COUNT};
+
+// the last flash page is reserved for persistent data, see memory.x
+const STORAGE_OFFSET: u32 = 63 * 1024;
+const STORAGE_PAGE_SIZE: usize = 1024;
+const CC_MAP_MAGIC: u8 = 0xCC;
+
+// flash is written by half words, reserve an even number of bytes
+const CC_MAP_SIZE: usize = (PARAMETERS_COUNT + 2) & !1;
+
+pub struct Storage {
+    flash: flash::Parts,
+}
+
+impl Storage {
+    pub fn new(flash: flash::Parts) -> Storage {
+        Storage { flash }
+    }
+
+    // load the CC map, return an empty map if none has been saved yet
+    pub fn load_cc_map(&mut self) -> CcMap {
+        let writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        match writer.read(STORAGE_OFFSET, CC_MAP_SIZE) {
+            Ok(bytes) if bytes[0] == CC_MAP_MAGIC => CcMap::from_bytes(&bytes[1..]),
+            _ => CcMap::new(),
+        }
+    }
+
+    pub fn save_cc_map(&mut self, map: &CcMap) -> flash::Result<()> {
+        let mut data = [0xFF; CC_MAP_SIZE];
+        data[0] = CC_MAP_MAGIC;
+        data[1..=PARAMETERS_COUNT].copy_from_slice(&map.to_bytes());
+
+        let mut writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        writer.erase(STORAGE_OFFSET, STORAGE_PAGE_SIZE)?;
+        writer.write(STORAGE_OFFSET, &data)
+    }
+}
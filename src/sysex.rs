@@ -0,0 +1,268 @@
+use crate::constants::*;
+use crate::track::{Track, TRACK_SIZE};
+
+// SysEx messages used to dump and restore patterns:
+//
+//   F0 7D 53 <command> <version> <data...> <checksum> F7
+//
+// 7D is the manufacturer ID reserved for non-commercial use. Data is packed
+// into 7 bits: each group of up to 7 bytes is preceded by a byte holding
+// their most significant bits. The checksum is chosen so that the sum of the
+// packed data and the checksum is a multiple of 128.
+//
+// Track dumps hold a single record, project dumps hold one record per track.
+// A record is the track index followed by the serialized track.
+
+pub const SYSEX_START: u8 = 0xF0;
+pub const SYSEX_END: u8 = 0xF7;
+const MANUFACTURER_ID: u8 = 0x7D;
+const DEVICE_ID: u8 = 0x53;
+
+// bumped whenever the serialized track format changes
+pub const DUMP_VERSION: u8 = 2;
+
+// commands
+const TRACK_DUMP: u8 = 0x01;
+const PROJECT_DUMP: u8 = 0x02;
+const TRACK_REQUEST: u8 = 0x03;
+const PROJECT_REQUEST: u8 = 0x04;
+const NAK: u8 = 0x7F;
+
+const HEADER_SIZE: usize = 5;
+const RECORD_SIZE: usize = 1 + TRACK_SIZE;
+const PROJECT_SIZE: usize = TRACKS_COUNT * RECORD_SIZE;
+
+const fn packed_size(size: usize) -> usize {
+    size + (size + 6) / 7
+}
+
+// size of the largest SysEx message, a project dump
+pub const SYSEX_MAX_SIZE: usize = HEADER_SIZE + packed_size(PROJECT_SIZE) + 2;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SysexError {
+    // message is not addressed to the sequencer
+    Ignored,
+    Malformed,
+    VersionMismatch(u8),
+    BadChecksum,
+    InvalidData,
+    UnknownCommand(u8),
+}
+
+impl SysexError {
+    // error code sent back to the host when a message is rejected
+    fn code(&self) -> u8 {
+        match self {
+            SysexError::Ignored => 0,
+            SysexError::Malformed => 1,
+            SysexError::VersionMismatch(_) => 2,
+            SysexError::BadChecksum => 3,
+            SysexError::InvalidData => 4,
+            SysexError::UnknownCommand(_) => 5,
+        }
+    }
+}
+
+// successfully decoded message
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sysex {
+    TrackLoaded(usize),
+    ProjectLoaded,
+    TrackRequest(usize),
+    ProjectRequest,
+}
+
+// encode a single track dump, return the message length
+pub fn encode_track(index: usize, track: &Track, out: &mut [u8; SYSEX_MAX_SIZE]) -> usize {
+    let mut data = [0; RECORD_SIZE];
+    data[0] = index as u8;
+    data[1..].copy_from_slice(&track.to_bytes());
+    encode(TRACK_DUMP, &data, out)
+}
+
+// encode a dump of all the tracks, return the message length
+pub fn encode_project(tracks: &[Track; TRACKS_COUNT], out: &mut [u8; SYSEX_MAX_SIZE]) -> usize {
+    let mut data = [0; PROJECT_SIZE];
+    for (i, record) in data.chunks_exact_mut(RECORD_SIZE).enumerate() {
+        record[0] = i as u8;
+        record[1..].copy_from_slice(&tracks[i].to_bytes());
+    }
+    encode(PROJECT_DUMP, &data, out)
+}
+
+// encode the reply sent when a message is rejected
+pub fn encode_nak(error: SysexError, out: &mut [u8; SYSEX_MAX_SIZE]) -> usize {
+    encode(NAK, &[error.code(), DUMP_VERSION], out)
+}
+
+fn encode(command: u8, data: &[u8], out: &mut [u8]) -> usize {
+    out[..HEADER_SIZE].copy_from_slice(&[
+        SYSEX_START,
+        MANUFACTURER_ID,
+        DEVICE_ID,
+        command,
+        DUMP_VERSION,
+    ]);
+    let mut len = HEADER_SIZE;
+    let mut sum: u8 = 0;
+
+    for group in data.chunks(7) {
+        let msbs = group
+            .iter()
+            .enumerate()
+            .fold(0, |msbs, (i, byte)| msbs | ((byte >> 7) << i));
+        out[len] = msbs;
+        sum = sum.wrapping_add(msbs);
+        len += 1;
+        for byte in group {
+            out[len] = byte & 0x7F;
+            sum = sum.wrapping_add(out[len]);
+            len += 1;
+        }
+    }
+
+    out[len] = 0u8.wrapping_sub(sum) & 0x7F;
+    out[len + 1] = SYSEX_END;
+    len + 2
+}
+
+// decode a SysEx message, dumps are loaded into the given tracks. Tracks are
+// only modified when the whole dump is valid
+pub fn decode(message: &[u8], tracks: &mut [Track; TRACKS_COUNT]) -> Result<Sysex, SysexError> {
+    let len = message.len();
+    if len < HEADER_SIZE + 2 || message[0] != SYSEX_START || message[len - 1] != SYSEX_END {
+        return Err(SysexError::Malformed);
+    }
+    if message[1] != MANUFACTURER_ID || message[2] != DEVICE_ID {
+        return Err(SysexError::Ignored);
+    }
+    let (command, version) = (message[3], message[4]);
+    if version != DUMP_VERSION {
+        return Err(SysexError::VersionMismatch(version));
+    }
+
+    let packed = &message[HEADER_SIZE..len - 1];
+    if packed.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) & 0x7F != 0 {
+        return Err(SysexError::BadChecksum);
+    }
+
+    let mut data = [0; PROJECT_SIZE];
+    let size = unpack(&packed[..packed.len() - 1], &mut data)?;
+    let data = &data[..size];
+
+    match command {
+        TRACK_DUMP => load_records(data, 1, tracks).map(Sysex::TrackLoaded),
+        PROJECT_DUMP => load_records(data, TRACKS_COUNT, tracks).map(|_| Sysex::ProjectLoaded),
+        TRACK_REQUEST => match data.first() {
+            Some(index) if (*index as usize) < TRACKS_COUNT => {
+                Ok(Sysex::TrackRequest(*index as usize))
+            }
+            _ => Err(SysexError::InvalidData),
+        },
+        PROJECT_REQUEST => Ok(Sysex::ProjectRequest),
+        _ => Err(SysexError::UnknownCommand(command)),
+    }
+}
+
+// restore track records, return the index of the last loaded track
+fn load_records(
+    data: &[u8],
+    count: usize,
+    tracks: &mut [Track; TRACKS_COUNT],
+) -> Result<usize, SysexError> {
+    let mut loaded = *tracks;
+    let mut position = 0;
+    let mut index = 0;
+
+    for _ in 0..count {
+        index = *data.get(position).ok_or(SysexError::InvalidData)? as usize;
+        if index >= TRACKS_COUNT {
+            return Err(SysexError::InvalidData);
+        }
+        position += 1 + loaded[index]
+            .load_bytes(&data[position + 1..])
+            .ok_or(SysexError::InvalidData)?;
+    }
+
+    *tracks = loaded;
+    Ok(index)
+}
+
+// unpack 7 bits data, return the number of bytes written
+fn unpack(packed: &[u8], out: &mut [u8]) -> Result<usize, SysexError> {
+    let mut len = 0;
+    for group in packed.chunks(8) {
+        let msbs = group[0];
+        if msbs > 0x7F {
+            return Err(SysexError::Malformed);
+        }
+        for (i, byte) in group[1..].iter().enumerate() {
+            if *byte > 0x7F || len >= out.len() {
+                return Err(SysexError::Malformed);
+            }
+            out[len] = byte | (((msbs >> i) & 1) << 7);
+            len += 1;
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::TrackMode;
+
+    #[test]
+    fn project_dump() {
+        let mut tracks = [Track::new(); TRACKS_COUNT];
+        tracks[2].set_mode(TrackMode::VELOCITY).set_glide(250);
+        tracks[7].set_track_length(3).set_swing(Some(20));
+        let mut message = [0; SYSEX_MAX_SIZE];
+        let len = encode_project(&tracks, &mut message);
+        assert_eq!(len, SYSEX_MAX_SIZE);
+        assert_eq!(
+            message[..HEADER_SIZE],
+            [0xF0, 0x7D, 0x53, 0x02, DUMP_VERSION]
+        );
+
+        let mut loaded = [Track::new(); TRACKS_COUNT];
+        assert_eq!(
+            decode(&message[..len], &mut loaded),
+            Ok(Sysex::ProjectLoaded)
+        );
+        assert_eq!(loaded[2].get_mode(), TrackMode::VELOCITY);
+        assert_eq!(loaded[2].get_glide(), 250);
+        assert_eq!(loaded[7].get_track_length(), 3);
+        assert_eq!(loaded[7].get_swing(), Some(20));
+        assert_eq!(loaded[0].get_swing(), None);
+    }
+
+    #[test]
+    fn rejected_dumps() {
+        let mut tracks = [Track::new(); TRACKS_COUNT];
+        tracks[1].set_glide(100);
+        let mut message = [0; SYSEX_MAX_SIZE];
+        let len = encode_track(1, &tracks[1], &mut message);
+
+        let mut loaded = [Track::new(); TRACKS_COUNT];
+        let mut old = message;
+        old[4] = 1;
+        assert_eq!(
+            decode(&old[..len], &mut loaded),
+            Err(SysexError::VersionMismatch(1))
+        );
+        let mut corrupted = message;
+        corrupted[HEADER_SIZE + 1] ^= 1;
+        assert_eq!(
+            decode(&corrupted[..len], &mut loaded),
+            Err(SysexError::BadChecksum)
+        );
+        assert_eq!(loaded[1].get_glide(), 0);
+        assert_eq!(
+            decode(&message[..len], &mut loaded),
+            Ok(Sysex::TrackLoaded(1))
+        );
+        assert_eq!(loaded[1].get_glide(), 100);
+    }
+}
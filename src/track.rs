@@ -2,15 +2,30 @@ use rand::distributions::{Distribution, Standard};
 use rand::rngs::SmallRng;
 use rand::RngCore;
 use rand::{Rng, SeedableRng};
-use rtt_target::rprintln;
 
 use crate::constants::*;
-
-// Track can either be Gate or CV out
+use crate::pitch::{Pitch, MAX_OCTAVE, MIN_OCTAVE};
+
+// size in bytes of a serialized step
+pub const STEP_SIZE: usize = 12;
+// size of the shortest serialized step, without tie, gate length, ratchets,
+// probability, condition and slide, as written by pattern-convert
+const MIN_STEP_SIZE: usize = 4;
+// size in bytes of the serialized track settings: mode, length, transpose,
+// step count, step size, glide (16 bits little endian) and swing
+const TRACK_HEADER_SIZE: usize = 8;
+// serialized swing of the tracks using the swing of the settings
+const NO_SWING: u8 = 0xFF;
+// size in bytes of a serialized track, its settings then the steps
+pub const TRACK_SIZE: usize = TRACK_HEADER_SIZE + STEPS_COUNT * STEP_SIZE;
+
+// Track can either be Gate, CV or Velocity out, a velocity track outputs
+// the velocity of its steps as an accent CV while their gate is open
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TrackMode {
     GATE,
     CV,
+    VELOCITY,
 }
 
 // Gate state
@@ -20,21 +35,143 @@ pub enum Gate {
     OFF,
 }
 
-// Note name
+// condition for a step to be played, cycles of the track being counted from
+// its start
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    Always,
+    // first cycle only
+    First,
+    // the a-th cycle of every b cycles, ie: Cycle(2, 4) plays on the 2nd, 6th,
+    // 10th cycles
+    Cycle(u8, u8),
+    // the last conditional step of the track was played, or was not
+    Previous,
+    NotPrevious,
+    // fill mode is on, or off
+    Fill,
+    NotFill,
+}
+
+impl Condition {
+    pub fn to_bytes(&self) -> [u8; 2] {
+        match *self {
+            Condition::Always => [0, 0],
+            Condition::First => [1, 0],
+            Condition::Cycle(a, b) => [2, a << 4 | b],
+            Condition::Previous => [3, 0],
+            Condition::NotPrevious => [4, 0],
+            Condition::Fill => [5, 0],
+            Condition::NotFill => [6, 0],
+        }
+    }
+
+    pub fn from_bytes(bytes: [u8; 2]) -> Option<Condition> {
+        let condition = match bytes[0] {
+            0 => Condition::Always,
+            1 => Condition::First,
+            2 => Condition::cycle(bytes[1] >> 4, bytes[1] & 0x0F)?,
+            3 => Condition::Previous,
+            4 => Condition::NotPrevious,
+            5 => Condition::Fill,
+            6 => Condition::NotFill,
+            _ => return None,
+        };
+        Some(condition)
+    }
+
+    // the a-th cycle of every b cycles, None if a or b is out of range
+    pub fn cycle(a: u8, b: u8) -> Option<Condition> {
+        if !(2..=MAX_CONDITION_CYCLES).contains(&b) || a == 0 || a > b {
+            return None;
+        }
+        Some(Condition::Cycle(a, b))
+    }
+
+    // conditions depending on the previous conditional step do not count as
+    // one
+    fn is_previous(&self) -> bool {
+        matches!(self, Condition::Previous | Condition::NotPrevious)
+    }
+}
+
+// live recording, Replace erases the steps passed without input
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordMode {
+    Overdub,
+    Replace,
+}
+
+// Note name, in pitch order
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Note {
-    A,
-    Ab,
-    B,
-    Bb,
     C,
-    D,
     Db,
-    E,
+    D,
     Eb,
+    E,
     F,
-    G,
     Gb,
+    G,
+    Ab,
+    A,
+    Bb,
+    B,
+}
+
+impl Note {
+    // note from a semitone offset from C
+    pub fn from_semitone(semitone: u8) -> Note {
+        match semitone % 12 {
+            0 => Note::C,
+            1 => Note::Db,
+            2 => Note::D,
+            3 => Note::Eb,
+            4 => Note::E,
+            5 => Note::F,
+            6 => Note::Gb,
+            7 => Note::G,
+            8 => Note::Ab,
+            9 => Note::A,
+            10 => Note::Bb,
+            _ => Note::B,
+        }
+    }
+
+    // semitone offset from C
+    pub fn semitone(&self) -> u8 {
+        match self {
+            Note::C => 0,
+            Note::Db => 1,
+            Note::D => 2,
+            Note::Eb => 3,
+            Note::E => 4,
+            Note::F => 5,
+            Note::Gb => 6,
+            Note::G => 7,
+            Note::Ab => 8,
+            Note::A => 9,
+            Note::Bb => 10,
+            Note::B => 11,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Note::C => "C",
+            Note::Db => "Db",
+            Note::D => "D",
+            Note::Eb => "Eb",
+            Note::E => "E",
+            Note::F => "F",
+            Note::Gb => "Gb",
+            Note::G => "G",
+            Note::Ab => "Ab",
+            Note::A => "A",
+            Note::Bb => "Bb",
+            Note::B => "B",
+        }
+    }
 }
 
 // Step can be a note or gate
@@ -44,17 +181,175 @@ pub struct Step {
     pub note: Note,
     pub octave: i8,
     pub velocity: u8,
+    // hold the note until the next step
+    pub tie: bool,
+    // gate length in percent of the step, 0 for the gate length of the
+    // settings
+    pub gate_length: u8,
+    // number of gates evenly spread over the step, 1 to MAX_RATCHETS
+    pub ratchets: u8,
+    // chance of the step to be played, in percent
+    pub probability: u8,
+    pub condition: Condition,
+    // glide from the previous note to the note of the step
+    pub slide: bool,
+    // micro-timing offset from the grid, in percent of a step
+    pub nudge: i8,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Step {
+    pub fn new() -> Step {
+        Step {
+            gate: Gate::OFF,
+            velocity: 255,
+            octave: 0,
+            note: Note::C,
+            tie: false,
+            gate_length: 0,
+            ratchets: 1,
+            probability: 100,
+            condition: Condition::Always,
+            slide: false,
+            nudge: 0,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; STEP_SIZE] {
+        let condition = self.condition.to_bytes();
+        [
+            (self.gate == Gate::ON) as u8,
+            self.note.semitone(),
+            self.octave as u8,
+            self.velocity,
+            self.tie as u8,
+            self.gate_length,
+            self.ratchets,
+            self.probability,
+            condition[0],
+            condition[1],
+            self.slide as u8,
+            self.nudge as u8,
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Step> {
+        if bytes.len() < MIN_STEP_SIZE
+            || bytes[0] > 1
+            || bytes[1] > 11
+            || !(MIN_OCTAVE..=MAX_OCTAVE).contains(&(bytes[2] as i8))
+            || matches!(bytes.get(5), Some(length) if *length > 100)
+            || matches!(bytes.get(6), Some(ratchets) if *ratchets == 0 || *ratchets > MAX_RATCHETS)
+            || matches!(bytes.get(7), Some(probability) if *probability > 100)
+            || matches!(bytes.get(11), Some(nudge) if (*nudge as i8).unsigned_abs() > MAX_NUDGE)
+        {
+            return None;
+        }
+        let condition = match bytes.get(8..10) {
+            Some(condition) => Condition::from_bytes([condition[0], condition[1]])?,
+            None => Condition::Always,
+        };
+        Some(Step {
+            gate: if bytes[0] == 1 { Gate::ON } else { Gate::OFF },
+            note: Note::from_semitone(bytes[1]),
+            octave: bytes[2] as i8,
+            velocity: bytes[3],
+            tie: bytes.get(4) == Some(&1),
+            gate_length: bytes.get(5).copied().unwrap_or(0),
+            ratchets: bytes.get(6).copied().unwrap_or(1),
+            probability: bytes.get(7).copied().unwrap_or(100),
+            condition,
+            slide: bytes.get(10) == Some(&1),
+            nudge: bytes.get(11).map_or(0, |nudge| *nudge as i8),
+        })
+    }
+
+    // gate length in percent of the step, steps without their own length use
+    // the default one
+    pub fn gate_percent(&self, default: u8) -> u8 {
+        if self.gate_length == 0 {
+            default
+        } else {
+            self.gate_length
+        }
+    }
+
+    // the gate stays open until the next step
+    pub fn is_tied(&self) -> bool {
+        self.gate == Gate::ON && self.tie
+    }
+
+    // the step is played depending on its condition or probability
+    pub fn is_conditional(&self) -> bool {
+        self.condition != Condition::Always || self.probability < 100
+    }
+
+    pub fn pitch(&self) -> Pitch {
+        Pitch::from_note(self.note, self.octave)
+    }
+
+    pub fn set_pitch(&mut self, pitch: Pitch) -> &mut Self {
+        self.note = pitch.note();
+        self.octave = pitch.octave();
+        self
+    }
+
+    // MIDI note number, octave 0 is the octave of middle C
+    pub fn midi_note(&self) -> u8 {
+        self.pitch().midi()
+    }
+
+    // MIDI velocity, a velocity of 0 being a note off
+    pub fn midi_velocity(&self) -> u8 {
+        (self.velocity >> 1).max(1)
+    }
+
+    // velocity scaled to the 12 bits of a DAC output
+    pub fn velocity_cv(&self) -> u16 {
+        self.velocity as u16 * 16
+    }
+
+    // return the step shifted by a number of semitones, clamped to the MIDI
+    // range
+    pub fn transpose(&self, semitones: i8) -> Step {
+        let mut step = *self;
+        step.set_pitch(self.pitch() + semitones);
+        step
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct Track {
     cursor: usize,
+    // clock division, not implemented yet
+    #[allow(dead_code)]
     divide: u8,
     length: usize,
     pattern: [Step; STEPS_COUNT],
     play: bool,
     mode: TrackMode,
+    record: Option<RecordMode>,
+    // steps written during the current pass of live recording
+    recorded: [bool; STEPS_COUNT],
+    // cycles played since the track started, None until the first step of
+    // a rewound track
+    cycle: Option<u32>,
+    // the last conditional step was played
+    previous: bool,
+    fill: bool,
+    // the condition of the step at the cursor was not met
+    skipped: bool,
+    // glide time of the sliding steps in milliseconds
+    glide: u16,
+    // swing of the track, None for the swing of the settings
+    swing: Option<u8>,
     seed: u64,
+    transpose: i8,
 }
 
 impl Default for Track {
@@ -72,19 +367,141 @@ impl Track {
             seed: 0,
             play: true,
             mode: TrackMode::GATE,
-            pattern: [Step {
-                gate: Gate::OFF,
-                velocity: 255,
-                octave: 0,
-                note: Note::C,
-            }; STEPS_COUNT],
+            record: None,
+            recorded: [false; STEPS_COUNT],
+            cycle: Some(0),
+            previous: false,
+            fill: false,
+            skipped: false,
+            glide: 0,
+            swing: None,
+            transpose: 0,
+            pattern: [Step::new(); STEPS_COUNT],
+        }
+    }
+
+    // serialize track settings and pattern, used for pattern dumps
+    pub fn to_bytes(&self) -> [u8; TRACK_SIZE] {
+        let mut bytes = [0; TRACK_SIZE];
+        bytes[0] = match self.mode {
+            TrackMode::GATE => 0,
+            TrackMode::CV => 1,
+            TrackMode::VELOCITY => 2,
+        };
+        bytes[1] = self.length as u8;
+        bytes[2] = self.transpose as u8;
+        bytes[3] = STEPS_COUNT as u8;
+        bytes[4] = STEP_SIZE as u8;
+        bytes[5..7].copy_from_slice(&self.glide.to_le_bytes());
+        bytes[7] = self.swing.unwrap_or(NO_SWING);
+        for (i, step) in self.pattern.iter().enumerate() {
+            let start = TRACK_HEADER_SIZE + i * STEP_SIZE;
+            bytes[start..start + STEP_SIZE].copy_from_slice(&step.to_bytes());
+        }
+        bytes
+    }
+
+    // restore track settings and pattern, return the number of bytes read or
+    // None if the data is invalid, in which case the track is left untouched
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> Option<usize> {
+        if bytes.len() < TRACK_HEADER_SIZE {
+            return None;
+        }
+        let mode = match bytes[0] {
+            0 => TrackMode::GATE,
+            1 => TrackMode::CV,
+            2 => TrackMode::VELOCITY,
+            _ => return None,
+        };
+        let (length, step_count, step_size) =
+            (bytes[1] as usize, bytes[3] as usize, bytes[4] as usize);
+        let size = TRACK_HEADER_SIZE + step_count * step_size;
+        let glide = u16::from_le_bytes([bytes[5], bytes[6]]);
+        let swing = match bytes[7] {
+            NO_SWING => None,
+            swing => Some(swing),
+        };
+        if length == 0
+            || length > STEPS_COUNT
+            || step_count > STEPS_COUNT
+            || step_size < MIN_STEP_SIZE
+            || glide > MAX_GLIDE_MS
+            || matches!(swing, Some(swing) if swing > MAX_SWING)
+            || bytes.len() < size
+        {
+            return None;
+        }
+
+        let mut pattern = [Step::new(); STEPS_COUNT];
+        for (i, step) in pattern.iter_mut().take(step_count).enumerate() {
+            let start = TRACK_HEADER_SIZE + i * step_size;
+            *step = Step::from_bytes(&bytes[start..start + step_size])?;
+        }
+
+        self.mode = mode;
+        self.length = length;
+        self.transpose = bytes[2] as i8;
+        self.glide = glide;
+        self.swing = swing;
+        self.pattern = pattern;
+        if self.cursor >= self.length {
+            self.reset();
         }
+        Some(size)
     }
 
     pub fn get_track_length(&mut self) -> usize {
         self.length
     }
 
+    pub fn set_track_length(&mut self, length: usize) -> &mut Self {
+        self.length = length.clamp(1, STEPS_COUNT);
+        if self.cursor >= self.length {
+            self.reset();
+        }
+        self
+    }
+
+    pub fn get_transpose(&mut self) -> i8 {
+        self.transpose
+    }
+
+    pub fn set_transpose(&mut self, semitones: i8) -> &mut Self {
+        self.transpose = semitones;
+        self
+    }
+
+    pub fn get_glide(&mut self) -> u16 {
+        self.glide
+    }
+
+    pub fn set_glide(&mut self, glide: u16) -> &mut Self {
+        self.glide = glide.min(MAX_GLIDE_MS);
+        self
+    }
+
+    pub fn get_swing(&mut self) -> Option<u8> {
+        self.swing
+    }
+
+    pub fn set_swing(&mut self, swing: Option<u8>) -> &mut Self {
+        self.swing = swing.map(|swing| swing.min(MAX_SWING));
+        self
+    }
+
+    // swing of an even step, 0 for odd steps
+    pub fn get_step_swing(&mut self, index: usize, default: u8) -> u8 {
+        if index % 2 == 1 {
+            self.swing.unwrap_or(default)
+        } else {
+            0
+        }
+    }
+
+    pub fn get_step(&mut self, index: usize) -> Step {
+        self.pattern[index]
+    }
+
     pub fn get_gate(&mut self, index: usize) -> Gate {
         self.pattern[index].gate
     }
@@ -101,16 +518,50 @@ impl Track {
         self.pattern[self.cursor].note
     }
 
+    pub fn get_current_step(&mut self) -> Step {
+        self.pattern[self.cursor]
+    }
+
+    // current step as it should be played, with transposition applied and
+    // its gate closed when its condition was not met
+    pub fn get_playing_step(&mut self) -> Step {
+        let mut step = self.pattern[self.cursor].transpose(self.transpose);
+        if self.skipped {
+            step.gate = Gate::OFF;
+        }
+        step
+    }
+
+    pub fn get_pattern(&mut self) -> [Step; STEPS_COUNT] {
+        self.pattern
+    }
+
+    pub fn set_pattern(&mut self, pattern: [Step; STEPS_COUNT]) -> &mut Self {
+        self.pattern = pattern;
+        self
+    }
+
+    pub fn set_step(&mut self, index: usize, step: Step) -> &mut Self {
+        self.pattern[index] = step;
+        self
+    }
+
     pub fn get_cursor(&mut self) -> usize {
         self.cursor
     }
 
+    pub fn set_cursor(&mut self, index: usize) -> &mut Self {
+        self.cursor = index % self.length;
+        self
+    }
+
     pub fn get_mode(&mut self) -> TrackMode {
         self.mode
     }
 
     pub fn set_mode(&mut self, mode: TrackMode) -> &mut Self {
-        if mode == TrackMode::CV {
+        // keep playing while recording live
+        if mode == TrackMode::CV && self.record.is_none() {
             self.stop();
         }
         self.mode = mode;
@@ -118,16 +569,15 @@ impl Track {
     }
 
     pub fn toggle_mode(&mut self) -> &mut Self {
-        if self.mode == TrackMode::GATE {
-            self.set_mode(TrackMode::CV);
-        } else {
-            self.set_mode(TrackMode::GATE);
-        }
-        self
+        self.set_mode(match self.mode {
+            TrackMode::GATE => TrackMode::CV,
+            TrackMode::CV => TrackMode::VELOCITY,
+            TrackMode::VELOCITY => TrackMode::GATE,
+        })
     }
 
-    pub fn record_note(&mut self, note: Note) -> &mut Self {
-        self.pattern[self.cursor].note = note;
+    pub fn record_note(&mut self, pitch: Pitch) -> &mut Self {
+        self.pattern[self.cursor].set_pitch(pitch);
         if self.cursor < self.get_track_length() - 1 {
             self.cursor += 1;
         } else {
@@ -151,8 +601,56 @@ impl Track {
         self
     }
 
-    pub fn set_note(&mut self, index: usize, note: Note) -> &mut Self {
-        self.pattern[index].note = note;
+    // set the note and octave of a step and open its gate
+    pub fn set_note(&mut self, index: usize, pitch: Pitch) -> &mut Self {
+        self.pattern[index].set_pitch(pitch);
+        self.pattern[index].gate = Gate::ON;
+        self
+    }
+
+    // set the velocity of a step and open its gate
+    pub fn set_velocity(&mut self, index: usize, velocity: u8) -> &mut Self {
+        self.pattern[index].velocity = velocity;
+        self.pattern[index].gate = Gate::ON;
+        self
+    }
+
+    pub fn get_record_mode(&mut self) -> Option<RecordMode> {
+        self.record
+    }
+
+    pub fn set_record_mode(&mut self, mode: Option<RecordMode>) -> &mut Self {
+        self.record = mode;
+        self.recorded = [false; STEPS_COUNT];
+        self
+    }
+
+    pub fn is_recording(&mut self) -> bool {
+        self.play && self.record.is_some()
+    }
+
+    // step nearest to an input while playing, the step being played or the
+    // next one when the input is late
+    pub fn nearest_step(&mut self, late: bool) -> usize {
+        if late {
+            self.get_next_cursor()
+        } else {
+            self.cursor
+        }
+    }
+
+    // step played after the one at the cursor
+    pub fn get_next_cursor(&mut self) -> usize {
+        (self.cursor + 1) % self.length
+    }
+
+    // open the gate of a step recorded live, and set its note in CV mode
+    pub fn record_step(&mut self, index: usize, pitch: Option<Pitch>) -> &mut Self {
+        if let Some(pitch) = pitch {
+            self.pattern[index].set_pitch(pitch);
+        }
+        self.pattern[index].gate = Gate::ON;
+        self.recorded[index] = true;
         self
     }
 
@@ -160,15 +658,63 @@ impl Track {
         if !self.play {
             return self;
         }
+        // the step left is erased unless it was recorded during this pass
+        if self.record == Some(RecordMode::Replace) && !self.recorded[self.cursor] {
+            self.pattern[self.cursor].gate = Gate::OFF;
+        }
+        self.recorded[self.cursor] = false;
         if self.cursor < self.get_track_length() - 1 {
             self.cursor += 1;
         } else {
             self.reset();
+            self.cycle = Some(self.cycle.map_or(0, |cycle| cycle.wrapping_add(1)));
+        }
+        self.skipped = !self.is_triggered();
+        self
+    }
+
+    // evaluate the condition and probability of the step at the cursor, the
+    // result of conditional steps is kept for the Previous conditions
+    fn is_triggered(&mut self) -> bool {
+        let step = self.pattern[self.cursor];
+        if step.gate == Gate::OFF || !step.is_conditional() {
+            return true;
         }
+        let cycle = self.cycle.unwrap_or(0);
+        let met = match step.condition {
+            Condition::Always => true,
+            Condition::First => cycle == 0,
+            Condition::Cycle(a, b) => cycle % b as u32 == a as u32 - 1,
+            Condition::Previous => self.previous,
+            Condition::NotPrevious => !self.previous,
+            Condition::Fill => self.fill,
+            Condition::NotFill => !self.fill,
+        };
+        let triggered = met && (step.probability >= 100 || self.chance(step.probability));
+        if !step.condition.is_previous() {
+            self.previous = triggered;
+        }
+        triggered
+    }
+
+    // draw from the random generator of the track, true with a probability
+    // in percent
+    fn chance(&mut self, probability: u8) -> bool {
+        let mut rng = SmallRng::seed_from_u64(self.seed);
+        self.seed = rng.next_u64();
+        rng.gen_range(0..100) < probability
+    }
+
+    pub fn get_fill(&mut self) -> bool {
+        self.fill
+    }
+
+    pub fn set_fill(&mut self, fill: bool) -> &mut Self {
+        self.fill = fill;
         self
     }
 
-    pub fn randomize(&mut self, _probability: f64) -> &mut Self {
+    pub fn randomize(&mut self, probability: f64) -> &mut Self {
         // TODO: review seeding logic, executing seeding logic on every random
         // action is not really optimized
         let mut rng = SmallRng::seed_from_u64(self.seed);
@@ -176,19 +722,24 @@ impl Track {
         // set new seed with random u64
         self.seed = rng.next_u64();
 
-        // TODO: check how to implement probability
+        // probability defines the density of active gates
+        let probability = probability.clamp(0.0, 1.0);
         for i in 0..self.get_track_length() {
             self.pattern[i].note = rng.gen();
-            self.pattern[i].gate = rng.gen();
+            self.pattern[i].gate = if rng.gen_bool(probability) {
+                Gate::ON
+            } else {
+                Gate::OFF
+            };
         }
-        rprintln!("Randomized pattern {:?}", self.pattern);
         self
     }
 
+    // reset the steps played by the track, including their gate length,
+    // ties and the other step settings
     pub fn clear(&mut self) -> &mut Self {
-        for i in 0..self.get_track_length() {
-            self.pattern[i].gate = Gate::OFF;
-            self.pattern[i].note = Note::C;
+        for step in self.pattern.iter_mut().take(self.length) {
+            *step = Step::new();
         }
         self
     }
@@ -198,6 +749,15 @@ impl Track {
         self
     }
 
+    // move cursor to the last step so that the next tick plays the first step
+    // of the first cycle
+    pub fn rewind(&mut self) -> &mut Self {
+        self.cursor = self.get_track_length() - 1;
+        self.cycle = None;
+        self.skipped = false;
+        self
+    }
+
     pub fn play(&mut self) -> &mut Self {
         self.play = true;
         self
@@ -215,15 +775,13 @@ impl Track {
     pub fn stop(&mut self) -> &mut Self {
         self.play = false;
         self.reset();
+        self.cycle = Some(0);
+        self.skipped = false;
         self
     }
 
     pub fn toggle_pause(&mut self) -> &mut Self {
-        if self.is_playing() {
-            self.play = false;
-        } else {
-            self.play = true;
-        }
+        self.play = !self.play;
         self
     }
 
@@ -261,3 +819,134 @@ impl Distribution<Note> for Standard {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step() -> Step {
+        Step {
+            gate: Gate::ON,
+            velocity: 100,
+            octave: -2,
+            note: Note::Gb,
+            tie: true,
+            gate_length: 25,
+            ratchets: 4,
+            probability: 50,
+            condition: Condition::Fill,
+            slide: true,
+            nudge: -20,
+        }
+    }
+
+    #[test]
+    fn track_bytes() {
+        let mut track = Track::new();
+        track
+            .set_mode(TrackMode::CV)
+            .set_track_length(5)
+            .set_transpose(-7)
+            .set_glide(1500)
+            .set_swing(Some(MAX_SWING))
+            .set_step(4, step());
+        let bytes = track.to_bytes();
+        assert_eq!(
+            bytes[..TRACK_HEADER_SIZE],
+            [1, 5, 0xF9, 8, 12, 0xDC, 0x05, 50]
+        );
+
+        let mut loaded = Track::new();
+        assert_eq!(loaded.load_bytes(&bytes), Some(TRACK_SIZE));
+        assert_eq!(loaded.get_mode(), TrackMode::CV);
+        assert_eq!(loaded.get_track_length(), 5);
+        assert_eq!(loaded.get_transpose(), -7);
+        assert_eq!(loaded.get_glide(), 1500);
+        assert_eq!(loaded.get_swing(), Some(MAX_SWING));
+        assert_eq!(loaded.get_pattern(), track.get_pattern());
+
+        // tracks using the swing of the settings
+        track.set_swing(None);
+        let bytes = track.to_bytes();
+        assert_eq!(bytes[7], NO_SWING);
+        assert_eq!(loaded.load_bytes(&bytes), Some(TRACK_SIZE));
+        assert_eq!(loaded.get_swing(), None);
+    }
+
+    #[test]
+    fn step_bytes() {
+        let bytes = step().to_bytes();
+        assert_eq!(Step::from_bytes(&bytes), Some(step()));
+        for octave in [MIN_OCTAVE, MAX_OCTAVE] {
+            let mut valid = bytes;
+            valid[2] = octave as u8;
+            assert_eq!(
+                Step::from_bytes(&valid).map(|step| step.octave),
+                Some(octave)
+            );
+        }
+        for octave in [MIN_OCTAVE - 1, MAX_OCTAVE + 1, i8::MIN] {
+            let mut invalid = bytes;
+            invalid[2] = octave as u8;
+            assert_eq!(Step::from_bytes(&invalid), None);
+        }
+    }
+
+    #[test]
+    fn transpose_steps() {
+        let transposed = step().transpose(7);
+        assert_eq!((transposed.note, transposed.octave), (Note::Db, -1));
+        assert_eq!(transposed.pitch(), step().pitch() + 7);
+        assert_eq!(
+            Step {
+                note: Note::Gb,
+                ..transposed
+            },
+            step().transpose(12)
+        );
+        let transposed = step().transpose(-19);
+        assert_eq!((transposed.note, transposed.octave), (Note::B, -4));
+        // pitches are clamped to the MIDI range
+        assert_eq!(step().transpose(i8::MAX).pitch(), Pitch::new(127));
+        assert_eq!(step().transpose(i8::MIN).pitch(), Pitch::new(0));
+    }
+
+    #[test]
+    fn invalid_track_bytes() {
+        let mut track = Track::new();
+        track.set_glide(100).set_swing(Some(10));
+        let bytes = track.to_bytes();
+        let mut loaded = Track::new();
+        for (index, value) in [
+            (0, 3),
+            (1, 0),
+            (1, 9),
+            (4, 3),
+            (6, 0x08),
+            (7, MAX_SWING + 1),
+        ] {
+            let mut invalid = bytes;
+            invalid[index] = value;
+            assert_eq!(loaded.load_bytes(&invalid), None);
+        }
+        assert_eq!(loaded.load_bytes(&bytes[..TRACK_SIZE - 1]), None);
+        assert_eq!(loaded.load_bytes(&bytes[..TRACK_HEADER_SIZE - 1]), None);
+        // the track is left untouched
+        assert_eq!((loaded.get_glide(), loaded.get_swing()), (0, None));
+    }
+
+    #[test]
+    fn clear_resets_steps() {
+        let mut track = Track::new();
+        track.set_pattern([step(); STEPS_COUNT]);
+        track.set_track_length(STEPS_COUNT - 2);
+        track.clear();
+
+        let pattern = track.get_pattern();
+        assert!(pattern[..STEPS_COUNT - 2]
+            .iter()
+            .all(|step| *step == Step::new()));
+        // steps past the length of the track are kept
+        assert_eq!(pattern[STEPS_COUNT - 2..], [step(); 2]);
+    }
+}
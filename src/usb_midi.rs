@@ -0,0 +1,158 @@
+use usb_device::class_prelude::*;
+use usb_device::{Result, UsbError};
+
+use crate::midi::{Message, PacketQueue};
+use crate::sysex::SYSEX_MAX_SIZE;
+
+// USB audio class codes (USB Device Class Definition for MIDI Devices 1.0)
+const USB_AUDIO_CLASS: u8 = 0x01;
+const USB_AUDIOCONTROL_SUBCLASS: u8 = 0x01;
+const USB_MIDISTREAMING_SUBCLASS: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+const HEADER_SUBTYPE: u8 = 0x01;
+const MS_HEADER_SUBTYPE: u8 = 0x01;
+const MS_GENERAL: u8 = 0x01;
+const MIDI_IN_JACK_SUBTYPE: u8 = 0x02;
+const MIDI_OUT_JACK_SUBTYPE: u8 = 0x03;
+const EMBEDDED: u8 = 0x01;
+
+const MIDI_IN_JACK_ID: u8 = 0x01;
+const MIDI_OUT_JACK_ID: u8 = 0x02;
+
+// total length of the class specific MIDI streaming descriptors: header,
+// in jack, out jack, and both bulk endpoints with their class descriptor
+const MS_TOTAL_LENGTH: u8 = 7 + 6 + 9 + 7 + 5 + 7 + 5;
+
+pub const MIDI_PACKET_SIZE: usize = 64;
+
+// USB-MIDI event packets waiting to be sent, large enough to hold a full
+// SysEx dump (3 bytes per packet) and a few messages
+const TX_QUEUE_SIZE: usize = SYSEX_MAX_SIZE / 3 + 16;
+
+// class compliant USB MIDI device with one embedded in and out jack
+pub struct MidiClass<'a, B: UsbBus> {
+    audio_control: InterfaceNumber,
+    midi_streaming: InterfaceNumber,
+    ep_out: EndpointOut<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+    tx_queue: PacketQueue<TX_QUEUE_SIZE>,
+}
+
+impl<B: UsbBus> MidiClass<'_, B> {
+    pub fn new(alloc: &UsbBusAllocator<B>) -> MidiClass<'_, B> {
+        MidiClass {
+            audio_control: alloc.interface(),
+            midi_streaming: alloc.interface(),
+            ep_out: alloc.bulk(MIDI_PACKET_SIZE as u16),
+            ep_in: alloc.bulk(MIDI_PACKET_SIZE as u16),
+            tx_queue: PacketQueue::new(),
+        }
+    }
+
+    // queue a message to be sent to the host
+    pub fn send(&mut self, message: Message) -> Result<()> {
+        self.tx_queue
+            .push_message(message, 0)
+            .map_err(|_| UsbError::BufferOverflow)?;
+        self.flush();
+        Ok(())
+    }
+
+    // queue a SysEx message to be sent to the host, the message is dropped
+    // if it does not fit in the queue
+    pub fn send_sysex(&mut self, sysex: &[u8]) -> Result<()> {
+        if !self.tx_queue.push_sysex(sysex, 0) {
+            return Err(UsbError::BufferOverflow);
+        }
+        self.flush();
+        Ok(())
+    }
+
+    // write as many queued packets as the endpoint can take
+    fn flush(&mut self) {
+        let mut buf = [0u8; MIDI_PACKET_SIZE];
+        let len = self.tx_queue.peek(&mut buf);
+        if len > 0 && self.ep_in.write(&buf[..len]).is_ok() {
+            self.tx_queue.consume(len);
+        }
+    }
+
+    // read raw USB-MIDI event packets sent by the host
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.ep_out.read(buf)
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.audio_control,
+            USB_AUDIO_CLASS,
+            USB_AUDIOCONTROL_SUBCLASS,
+            0,
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                HEADER_SUBTYPE,
+                0x00,
+                0x01, // bcdADC 1.0
+                0x09,
+                0x00, // wTotalLength
+                0x01, // bInCollection
+                self.midi_streaming.into(),
+            ],
+        )?;
+
+        writer.interface(
+            self.midi_streaming,
+            USB_AUDIO_CLASS,
+            USB_MIDISTREAMING_SUBCLASS,
+            0,
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[MS_HEADER_SUBTYPE, 0x00, 0x01, MS_TOTAL_LENGTH, 0x00],
+        )?;
+
+        // host -> device
+        writer.write(
+            CS_INTERFACE,
+            &[MIDI_IN_JACK_SUBTYPE, EMBEDDED, MIDI_IN_JACK_ID, 0x00],
+        )?;
+
+        // device -> host
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MIDI_OUT_JACK_SUBTYPE,
+                EMBEDDED,
+                MIDI_OUT_JACK_ID,
+                0x01, // bNrInputPins
+                MIDI_IN_JACK_ID,
+                0x01, // baSourcePin
+                0x00,
+            ],
+        )?;
+
+        writer.endpoint(&self.ep_out)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 0x01, MIDI_IN_JACK_ID])?;
+
+        writer.endpoint(&self.ep_in)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 0x01, MIDI_OUT_JACK_ID])?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.tx_queue.clear();
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.flush();
+        }
+    }
+}
@@ -0,0 +1,472 @@
+// SysEx pattern dump format, see the "SysEx pattern dumps" section of the
+// README for a description of the messages
+
+use std::error::Error;
+use std::fmt;
+
+pub const DUMP_VERSION: u8 = 2;
+pub const TRACKS_COUNT: usize = 8;
+// steps of a sequencer track
+pub const STEPS_COUNT: usize = 8;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const HEADER: [u8; 3] = [SYSEX_START, 0x7D, 0x53];
+const HEADER_SIZE: usize = 5;
+
+const TRACK_DUMP: u8 = 0x01;
+const PROJECT_DUMP: u8 = 0x02;
+
+// track index, mode, length, transpose, step count, step size, glide and
+// swing. Glide and swing have no MIDI equivalent and are not converted
+const RECORD_HEADER_SIZE: usize = 9;
+// swing of the tracks using the swing of the settings
+const NO_SWING: u8 = 0xFF;
+const STEP_SIZE: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Gate,
+    Cv,
+    Velocity,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    pub gate: bool,
+    // semitone offset from C
+    pub note: u8,
+    // octave 0 is the octave of middle C
+    pub octave: i8,
+    pub velocity: u8,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Step {
+            gate: false,
+            note: 0,
+            octave: 0,
+            velocity: 255,
+        }
+    }
+}
+
+impl Step {
+    pub fn from_midi(note: u8, velocity: u8) -> Step {
+        Step {
+            gate: true,
+            note: note % 12,
+            octave: (note / 12) as i8 - 5,
+            velocity: velocity.min(127) * 2 + 1,
+        }
+    }
+
+    pub fn midi_note(&self, transpose: i8) -> u8 {
+        (60 + self.octave as i16 * 12 + self.note as i16 + transpose as i16).clamp(0, 127) as u8
+    }
+
+    pub fn midi_velocity(&self) -> u8 {
+        (self.velocity >> 1).max(1)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    pub mode: Mode,
+    pub length: usize,
+    pub transpose: i8,
+    pub steps: Vec<Step>,
+}
+
+impl Track {
+    pub fn new(steps: usize) -> Track {
+        Track {
+            mode: Mode::Gate,
+            length: steps,
+            transpose: 0,
+            steps: vec![Step::default(); steps],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DumpError {
+    NotADump,
+    VersionMismatch(u8),
+    BadChecksum,
+    InvalidData(&'static str),
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DumpError::NotADump => write!(f, "no sequencer dump found"),
+            DumpError::VersionMismatch(version) => write!(
+                f,
+                "dump version {} is not supported (expected {})",
+                version, DUMP_VERSION
+            ),
+            DumpError::BadChecksum => write!(f, "invalid checksum"),
+            DumpError::InvalidData(reason) => write!(f, "invalid dump: {}", reason),
+        }
+    }
+}
+
+impl Error for DumpError {}
+
+// return the first track or project dump found in a byte stream
+pub fn find_dump(bytes: &[u8]) -> Option<&[u8]> {
+    let mut start = 0;
+    while let Some(offset) = bytes[start..].iter().position(|b| *b == SYSEX_START) {
+        let begin = start + offset;
+        let end = begin + bytes[begin..].iter().position(|b| *b == SYSEX_END)?;
+        let message = &bytes[begin..=end];
+        if message.len() > HEADER_SIZE
+            && message.starts_with(&HEADER)
+            && matches!(message[3], TRACK_DUMP | PROJECT_DUMP)
+        {
+            return Some(message);
+        }
+        start = end + 1;
+    }
+    None
+}
+
+// decode a track or project dump, return the tracks with their index
+pub fn decode(message: &[u8]) -> Result<Vec<(usize, Track)>, DumpError> {
+    let len = message.len();
+    if len < HEADER_SIZE + 2 || !message.starts_with(&HEADER) || message[len - 1] != SYSEX_END {
+        return Err(DumpError::NotADump);
+    }
+    if message[4] != DUMP_VERSION {
+        return Err(DumpError::VersionMismatch(message[4]));
+    }
+
+    let packed = &message[HEADER_SIZE..len - 1];
+    if packed.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) & 0x7F != 0 {
+        return Err(DumpError::BadChecksum);
+    }
+    let data = unpack(&packed[..packed.len() - 1])?;
+
+    let count = match message[3] {
+        TRACK_DUMP => 1,
+        PROJECT_DUMP => TRACKS_COUNT,
+        _ => return Err(DumpError::NotADump),
+    };
+
+    let mut tracks = Vec::with_capacity(count);
+    let mut position = 0;
+    for _ in 0..count {
+        let record = data
+            .get(position..position + RECORD_HEADER_SIZE)
+            .ok_or(DumpError::InvalidData("truncated track"))?;
+        let (index, step_count, step_size) =
+            (record[0] as usize, record[4] as usize, record[5] as usize);
+        if index >= TRACKS_COUNT {
+            return Err(DumpError::InvalidData("track index out of range"));
+        }
+        if step_size < STEP_SIZE {
+            return Err(DumpError::InvalidData("step size too small"));
+        }
+        let mode = match record[1] {
+            0 => Mode::Gate,
+            1 => Mode::Cv,
+            2 => Mode::Velocity,
+            _ => return Err(DumpError::InvalidData("unknown track mode")),
+        };
+
+        let start = position + RECORD_HEADER_SIZE;
+        let steps_data = data
+            .get(start..start + step_count * step_size)
+            .ok_or(DumpError::InvalidData("truncated steps"))?;
+        let steps = steps_data
+            .chunks_exact(step_size)
+            .map(|step| Step {
+                gate: step[0] == 1,
+                note: step[1] % 12,
+                octave: step[2] as i8,
+                velocity: step[3],
+            })
+            .collect();
+
+        tracks.push((
+            index,
+            Track {
+                mode,
+                length: (record[2] as usize).clamp(1, step_count.max(1)),
+                transpose: record[3] as i8,
+                steps,
+            },
+        ));
+        position = start + step_count * step_size;
+    }
+    Ok(tracks)
+}
+
+// encode a project dump, missing tracks are sent empty. The sequencer
+// rejects tracks of more than 8 steps
+pub fn encode_project(tracks: &[Track]) -> Result<Vec<u8>, DumpError> {
+    if tracks.len() > TRACKS_COUNT {
+        return Err(DumpError::InvalidData("more than 8 tracks"));
+    }
+    if tracks.iter().any(|t| t.steps.len() > STEPS_COUNT) {
+        return Err(DumpError::InvalidData("more than 8 steps in a track"));
+    }
+    if tracks
+        .iter()
+        .any(|t| t.length == 0 || t.length > t.steps.len())
+    {
+        return Err(DumpError::InvalidData("track length out of range"));
+    }
+    let empty = Track::new(STEPS_COUNT);
+
+    let mut data = Vec::new();
+    for index in 0..TRACKS_COUNT {
+        let track = tracks.get(index).unwrap_or(&empty);
+        data.extend_from_slice(&[
+            index as u8,
+            match track.mode {
+                Mode::Gate => 0,
+                Mode::Cv => 1,
+                Mode::Velocity => 2,
+            },
+            track.length as u8,
+            track.transpose as u8,
+            track.steps.len() as u8,
+            STEP_SIZE as u8,
+            0,
+            0,
+            NO_SWING,
+        ]);
+        for step in &track.steps {
+            data.extend_from_slice(&[step.gate as u8, step.note, step.octave as u8, step.velocity]);
+        }
+    }
+
+    let mut message = HEADER.to_vec();
+    message.extend_from_slice(&[PROJECT_DUMP, DUMP_VERSION]);
+    let packed = pack(&data);
+    let sum = packed.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    message.extend_from_slice(&packed);
+    message.push(0u8.wrapping_sub(sum) & 0x7F);
+    message.push(SYSEX_END);
+    Ok(message)
+}
+
+fn pack(data: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(data.len() + data.len().div_ceil(7));
+    for group in data.chunks(7) {
+        packed.push(
+            group
+                .iter()
+                .enumerate()
+                .fold(0, |msbs, (i, b)| msbs | ((b >> 7) << i)),
+        );
+        packed.extend(group.iter().map(|b| b & 0x7F));
+    }
+    packed
+}
+
+fn unpack(packed: &[u8]) -> Result<Vec<u8>, DumpError> {
+    let mut data = Vec::with_capacity(packed.len());
+    for group in packed.chunks(8) {
+        if group.iter().any(|b| *b > 0x7F) {
+            return Err(DumpError::InvalidData("unexpected status byte"));
+        }
+        data.extend(
+            group[1..]
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b | (((group[0] >> i) & 1) << 7)),
+        );
+    }
+    Ok(data)
+}
+
+// parse a dump written as hexadecimal text, as printed by `amidi --dump` or
+// the serial console, whose lines are load commands
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|token| !token.is_empty() && *token != "load")
+        .map(|token| u8::from_str_radix(token.trim_start_matches("0x"), 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // track dump sent by the firmware (src/sysex.rs): record of track 3, a
+    // CV track of length 1 transposed by -2, with a glide of 250ms and the
+    // swing of the settings, holding one 12 bytes step E-1 with a velocity
+    // of 200
+    const FIRMWARE_TRACK_DUMP: [u8; 31] = [
+        0xF0, 0x7D, 0x53, 0x01, 0x02, // header
+        0x48, 0x03, 0x01, 0x01, 0x7E, 0x01, 0x0C, 0x7A, // index, track header
+        0x32, 0x00, 0x7F, 0x01, 0x04, 0x7F, 0x48, 0x00, // end of the header, step
+        0x00, 0x32, 0x01, 0x64, 0x00, 0x00, 0x00, 0x00, // end of the step
+        0x1A, 0xF7, // checksum
+    ];
+
+    fn tracks() -> Vec<Track> {
+        let mut first = Track::new(STEPS_COUNT);
+        first.mode = Mode::Velocity;
+        first.length = 6;
+        first.transpose = -5;
+        first.steps[0] = Step::from_midi(36, 127);
+        first.steps[7] = Step::from_midi(97, 10);
+        let mut second = Track::new(STEPS_COUNT);
+        second.steps[3] = Step::from_midi(0, 64);
+        vec![first, second]
+    }
+
+    #[test]
+    fn decode_firmware_dump() {
+        let step = Step {
+            gate: true,
+            note: 4,
+            octave: -1,
+            velocity: 200,
+        };
+        assert_eq!(
+            decode(&FIRMWARE_TRACK_DUMP),
+            Ok(vec![(
+                3,
+                Track {
+                    mode: Mode::Cv,
+                    length: 1,
+                    transpose: -2,
+                    steps: vec![step],
+                }
+            )])
+        );
+        assert_eq!(step.midi_note(-2), 50);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let tracks = tracks();
+        let message = encode_project(&tracks).unwrap();
+        assert_eq!(
+            message[..HEADER_SIZE],
+            [0xF0, 0x7D, 0x53, PROJECT_DUMP, DUMP_VERSION]
+        );
+        assert_eq!(message.last(), Some(&SYSEX_END));
+        assert!(message[1..message.len() - 1].iter().all(|b| *b <= 0x7F));
+
+        let decoded = decode(&message).unwrap();
+        assert_eq!(decoded.len(), TRACKS_COUNT);
+        for (i, (index, track)) in decoded.into_iter().enumerate() {
+            assert_eq!(index, i);
+            assert_eq!(
+                track,
+                tracks
+                    .get(i)
+                    .cloned()
+                    .unwrap_or_else(|| Track::new(STEPS_COUNT))
+            );
+        }
+    }
+
+    #[test]
+    fn encoded_dump_fits_the_firmware() {
+        // SYSEX_MAX_SIZE of src/sysex.rs: header, project of 8 records of an
+        // index, the 8 bytes track header and 8 steps of 12 bytes, packed,
+        // then checksum and end
+        let project_size = TRACKS_COUNT * (RECORD_HEADER_SIZE + STEPS_COUNT * 12);
+        let sysex_max_size = HEADER_SIZE + project_size + project_size.div_ceil(7) + 2;
+
+        let mut full = Track::new(STEPS_COUNT);
+        full.steps.fill(Step::from_midi(127, 127));
+        for tracks in [vec![], tracks(), vec![full; TRACKS_COUNT]] {
+            let message = encode_project(&tracks).unwrap();
+            assert!(message.len() <= sysex_max_size);
+
+            // the firmware loads tracks of at most 8 steps
+            let packed = &message[HEADER_SIZE..message.len() - 1];
+            let data = unpack(&packed[..packed.len() - 1]).unwrap();
+            let record_size = RECORD_HEADER_SIZE + STEPS_COUNT * STEP_SIZE;
+            assert_eq!(data.len(), TRACKS_COUNT * record_size);
+            for record in data.chunks(record_size) {
+                let (length, step_count) = (record[2] as usize, record[4] as usize);
+                assert!((1..=STEPS_COUNT).contains(&length));
+                assert!(length <= step_count && step_count <= STEPS_COUNT);
+            }
+        }
+
+        let too_long = Track::new(16);
+        assert_eq!(
+            encode_project(&[too_long]),
+            Err(DumpError::InvalidData("more than 8 steps in a track"))
+        );
+        let mut empty = Track::new(STEPS_COUNT);
+        empty.length = 0;
+        assert!(encode_project(&[empty]).is_err());
+        assert!(encode_project(&vec![Track::new(STEPS_COUNT); TRACKS_COUNT + 1]).is_err());
+    }
+
+    #[test]
+    fn pack_unpack() {
+        let data: Vec<u8> = (0..=255).collect();
+        let packed = pack(&data);
+        assert_eq!(packed.len(), 256 + 37);
+        assert_eq!(packed[..8], [0x00, 0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(unpack(&packed), Ok(data));
+        assert_eq!(pack(&[0x80, 0x7F, 0xFF]), [0b101, 0, 0x7F, 0x7F]);
+        assert!(unpack(&[0x00, 0x80]).is_err());
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut message = FIRMWARE_TRACK_DUMP;
+        message[12] ^= 1;
+        assert_eq!(decode(&message), Err(DumpError::BadChecksum));
+
+        let mut message = FIRMWARE_TRACK_DUMP;
+        message[4] = 1;
+        assert_eq!(decode(&message), Err(DumpError::VersionMismatch(1)));
+
+        assert_eq!(decode(&FIRMWARE_TRACK_DUMP[..30]), Err(DumpError::NotADump));
+        assert_eq!(
+            decode(&[0xF0, 0x7E, 0x53, 0x01, 0x01, 0x00, 0xF7]),
+            Err(DumpError::NotADump)
+        );
+
+        // the track header announces a step that is not in the dump
+        let message = [
+            0xF0, 0x7D, 0x53, 0x01, 0x02, 0x48, 0x03, 0x01, 0x01, 0x7E, 0x01, 0x0C, 0x7A, 0x02,
+            0x00, 0x7F, 0x2D, 0xF7,
+        ];
+        assert_eq!(
+            decode(&message),
+            Err(DumpError::InvalidData("truncated steps"))
+        );
+    }
+
+    #[test]
+    fn find_dump_in_stream() {
+        let mut bytes = b"text".to_vec();
+        // identity reply, not a dump
+        bytes.extend_from_slice(&[0xF0, 0x7E, 0x7F, 0x06, 0x02, 0xF7]);
+        bytes.extend_from_slice(&FIRMWARE_TRACK_DUMP);
+        bytes.extend_from_slice(&[0xF0, 0x7D, 0x53, 0x02]);
+        assert_eq!(find_dump(&bytes), Some(&FIRMWARE_TRACK_DUMP[..]));
+        assert_eq!(find_dump(&bytes[..20]), None);
+    }
+
+    #[test]
+    fn hex_text() {
+        assert_eq!(
+            parse_hex("F0 7d,0x53\n 01\t01  F7"),
+            Some(vec![0xF0, 0x7D, 0x53, 0x01, 0x01, 0xF7])
+        );
+        assert_eq!(
+            parse_hex("load F0 7D 53\nload 01 01 F7\n"),
+            Some(vec![0xF0, 0x7D, 0x53, 0x01, 0x01, 0xF7])
+        );
+        assert_eq!(parse_hex(""), Some(vec![]));
+        assert_eq!(parse_hex("F0 G1"), None);
+        assert_eq!(parse_hex("F00"), None);
+    }
+}
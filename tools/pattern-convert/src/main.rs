@@ -0,0 +1,178 @@
+// convert sequencer SysEx pattern dumps to and from Standard MIDI Files
+
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+
+mod dump;
+mod smf;
+
+use smf::Timings;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a track or project dump into a MIDI file
+    ToMidi {
+        /// SysEx dump, binary (.syx) or hexadecimal text
+        #[arg(required_unless_present = "port")]
+        input: Option<PathBuf>,
+        /// Capture the dump from a serial MIDI port instead of a file
+        #[arg(long, conflicts_with = "input")]
+        port: Option<String>,
+        #[arg(long, default_value_t = 31_250)]
+        baud_rate: u32,
+        /// MIDI file to write
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value_t = 120)]
+        bpm: u16,
+        /// Note value of a step, 4 for quarter notes
+        #[arg(long, default_value_t = 4, value_parser = division)]
+        division: u16,
+        /// Gate length in percent of a step
+        #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(1..=100))]
+        gate: u8,
+        /// Number of times the pattern is written
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        repeat: u16,
+    },
+    /// Convert a MIDI file into a project dump
+    FromMidi {
+        /// MIDI file to read
+        input: PathBuf,
+        /// SysEx dump to write
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Number of steps of the tracks, at most 8
+        #[arg(long, default_value_t = 8, value_parser = steps)]
+        steps: usize,
+        /// Note value of a step, 4 for quarter notes
+        #[arg(long, default_value_t = 4, value_parser = division)]
+        division: u16,
+        /// Write the dump as console load commands instead of binary
+        #[arg(long)]
+        hex: bool,
+    },
+}
+
+fn steps(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(steps @ 1..=dump::STEPS_COUNT) => Ok(steps),
+        _ => Err(format!(
+            "steps must be between 1 and {}, the steps of a sequencer track",
+            dump::STEPS_COUNT
+        )),
+    }
+}
+
+fn division(value: &str) -> Result<u16, String> {
+    match value.parse() {
+        Ok(division @ (1 | 2 | 4 | 8 | 16 | 32)) => Ok(division),
+        _ => Err("division must be 1, 2, 4, 8, 16 or 32".into()),
+    }
+}
+
+fn main() {
+    if let Err(error) = run(Cli::parse().command) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::ToMidi {
+            input,
+            port,
+            baud_rate,
+            output,
+            bpm,
+            division,
+            gate,
+            repeat,
+        } => {
+            let bytes = match (input, port) {
+                (Some(path), _) => read_input(&path)?,
+                (None, Some(port)) => capture(&port, baud_rate)?,
+                (None, None) => unreachable!(),
+            };
+            let message = dump::find_dump(&bytes).ok_or(dump::DumpError::NotADump)?;
+            let tracks = dump::decode(message)?;
+            smf::write(
+                &output,
+                &tracks,
+                &Timings { bpm, division },
+                gate,
+                repeat as usize,
+            )?;
+            println!("{} track(s) written to {}", tracks.len(), output.display());
+        }
+        Command::FromMidi {
+            input,
+            output,
+            steps,
+            division,
+            hex,
+        } => {
+            let tracks = smf::read(&input, steps, &Timings { bpm: 0, division })?;
+            let message = dump::encode_project(&tracks)?;
+            if hex {
+                // console load commands, in chunks fitting a console line
+                let text: String = message
+                    .chunks(32)
+                    .map(|chunk| {
+                        let bytes: Vec<String> =
+                            chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                        format!("load {}\n", bytes.join(" "))
+                    })
+                    .collect();
+                fs::write(&output, text)?;
+            } else {
+                fs::write(&output, &message)?;
+            }
+            println!("{} track(s) written to {}", tracks.len(), output.display());
+        }
+    }
+    Ok(())
+}
+
+// read a binary dump, or a dump written as hexadecimal text
+fn read_input(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let bytes = fs::read(path)?;
+    if bytes.first() == Some(&0xF0) {
+        return Ok(bytes);
+    }
+    std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(dump::parse_hex)
+        .ok_or_else(|| format!("{} is neither a SysEx nor a hex dump", path.display()).into())
+}
+
+// wait for a dump on a serial port, as sent with Fn2+Back or Fn2+Forward
+fn capture(port: &str, baud_rate: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut port = serialport::new(port, baud_rate)
+        .timeout(Duration::from_secs(30))
+        .open()?;
+    println!("waiting for a dump...");
+
+    let mut bytes = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = port.read(&mut buf)?;
+        bytes.extend_from_slice(&buf[..len]);
+        if dump::find_dump(&bytes).is_some() {
+            return Ok(bytes);
+        }
+    }
+}
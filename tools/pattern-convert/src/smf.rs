@@ -0,0 +1,322 @@
+// conversion between sequencer tracks and Standard MIDI Files
+
+use std::error::Error;
+use std::path::Path;
+
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::dump::{Mode, Step, Track, TRACKS_COUNT};
+
+// ticks per quarter note of the exported files
+const PPQ: u16 = 96;
+
+// timing options shared by both conversions
+pub struct Timings {
+    pub bpm: u16,
+    // note value of a step, 4 for quarter notes, 16 for sixteenth notes
+    pub division: u16,
+}
+
+impl Timings {
+    fn ticks_per_step(&self, ppq: u16) -> u32 {
+        ppq as u32 * 4 / self.division as u32
+    }
+}
+
+// write one MIDI track per sequencer track, on the channel of the track
+pub fn write(
+    path: &Path,
+    tracks: &[(usize, Track)],
+    timings: &Timings,
+    gate_length: u8,
+    repeat: usize,
+) -> Result<(), Box<dyn Error>> {
+    std::fs::write(path, encode(tracks, timings, gate_length, repeat)?)?;
+    Ok(())
+}
+
+fn encode(
+    tracks: &[(usize, Track)],
+    timings: &Timings,
+    gate_length: u8,
+    repeat: usize,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let step_ticks = timings.ticks_per_step(PPQ);
+    let gate_ticks = (step_ticks * gate_length.clamp(1, 100) as u32 / 100).max(1);
+    let names: Vec<String> = tracks
+        .iter()
+        .map(|(index, _)| format!("Track {}", index + 1))
+        .collect();
+
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(PPQ)),
+    ));
+    smf.tracks.push(vec![
+        TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(
+                60_000_000 / timings.bpm.max(1) as u32,
+            ))),
+        },
+        end_of_track(0),
+    ]);
+
+    for ((index, track), name) in tracks.iter().zip(&names) {
+        let channel = u4::new(*index as u8);
+        // (tick, note off first, event) sorted to compute deltas
+        let mut events = Vec::new();
+        let length = track.length.min(track.steps.len());
+        for (i, step) in track.steps[..length]
+            .iter()
+            .cycle()
+            .take(length * repeat)
+            .enumerate()
+        {
+            if !step.gate {
+                continue;
+            }
+            let start = i as u32 * step_ticks;
+            let key = u7::new(step.midi_note(track.transpose));
+            let vel = u7::new(step.midi_velocity());
+            events.push((start, 1, MidiMessage::NoteOn { key, vel }));
+            events.push((
+                start + gate_ticks,
+                0,
+                MidiMessage::NoteOff {
+                    key,
+                    vel: u7::new(0),
+                },
+            ));
+        }
+        events.sort_by_key(|(tick, order, _)| (*tick, *order));
+
+        let mut smf_track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+        }];
+        let mut last = 0;
+        for (tick, _, message) in events {
+            smf_track.push(TrackEvent {
+                delta: u28::new(tick - last),
+                kind: TrackEventKind::Midi { channel, message },
+            });
+            last = tick;
+        }
+        let end = (length * repeat) as u32 * step_ticks;
+        smf_track.push(end_of_track(end.saturating_sub(last)));
+        smf.tracks.push(smf_track);
+    }
+
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn end_of_track(delta: u32) -> TrackEvent<'static> {
+    TrackEvent {
+        delta: u28::new(delta),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    }
+}
+
+// key and velocity of the note played on a step
+type Note = Option<(u8, u8)>;
+
+// read notes from a MIDI file, quantized to the given number of steps. Each
+// MIDI track with notes becomes a sequencer track, single track files are
+// split by channel. When several notes fall on the same step the highest one
+// is kept
+pub fn read(path: &Path, steps: usize, timings: &Timings) -> Result<Vec<Track>, Box<dyn Error>> {
+    decode(&std::fs::read(path)?, steps, timings)
+}
+
+fn decode(bytes: &[u8], steps: usize, timings: &Timings) -> Result<Vec<Track>, Box<dyn Error>> {
+    let smf = Smf::parse(bytes)?;
+    let ppq = match smf.header.timing {
+        Timing::Metrical(ppq) => ppq.as_int(),
+        Timing::Timecode(..) => return Err("timecode based MIDI files are not supported".into()),
+    };
+    let step_ticks = timings.ticks_per_step(ppq).max(1);
+    let split_channels = smf.tracks.len() == 1;
+
+    // notes grouped by source, a track index or a channel
+    let mut sources: Vec<(usize, Vec<Note>)> = Vec::new();
+    for (track_index, smf_track) in smf.tracks.iter().enumerate() {
+        let mut tick = 0;
+        for event in smf_track {
+            tick += event.delta.as_int();
+            let (channel, key, vel) = match event.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, vel },
+                } if vel > 0 => (channel.as_int(), key.as_int(), vel.as_int()),
+                _ => continue,
+            };
+            let step = ((tick + step_ticks / 2) / step_ticks) as usize;
+            if step >= steps {
+                continue;
+            }
+            let source = if split_channels {
+                channel as usize
+            } else {
+                track_index
+            };
+            let notes = match sources.iter().position(|(s, _)| *s == source) {
+                Some(i) => &mut sources[i].1,
+                None => {
+                    sources.push((source, vec![None; steps]));
+                    &mut sources.last_mut().unwrap().1
+                }
+            };
+            if !matches!(notes[step], Some((note, _)) if note >= key) {
+                notes[step] = Some((key, vel));
+            }
+        }
+    }
+
+    if sources.is_empty() {
+        return Err("no notes found".into());
+    }
+    if sources.len() > TRACKS_COUNT {
+        eprintln!(
+            "warning: only the first {} of {} tracks are converted",
+            TRACKS_COUNT,
+            sources.len()
+        );
+    }
+    sources.sort_by_key(|(source, _)| *source);
+
+    Ok(sources
+        .iter()
+        .take(TRACKS_COUNT)
+        .map(|(_, notes)| {
+            let mut track = Track::new(steps);
+            for (step, note) in track.steps.iter_mut().zip(notes) {
+                if let Some((key, vel)) = note {
+                    *step = Step::from_midi(*key, *vel);
+                }
+            }
+            // a single pitch only needs a gate track
+            let mut pitches = notes.iter().flatten().map(|(key, _)| key);
+            let first = pitches.next();
+            if pitches.any(|key| Some(key) != first) {
+                track.mode = Mode::Cv;
+            }
+            track
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dump::STEPS_COUNT;
+
+    const SIXTEENTHS: Timings = Timings {
+        bpm: 120,
+        division: 16,
+    };
+
+    fn note_on(delta: u32, channel: u8, key: u8, vel: u8) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Midi {
+                channel: u4::new(channel),
+                message: MidiMessage::NoteOn {
+                    key: u7::new(key),
+                    vel: u7::new(vel),
+                },
+            },
+        }
+    }
+
+    fn smf_bytes(tracks: Vec<Vec<TrackEvent<'static>>>) -> Vec<u8> {
+        let mut smf = Smf::new(Header::new(
+            Format::Parallel,
+            Timing::Metrical(u15::new(PPQ)),
+        ));
+        smf.tracks = tracks;
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut melody = Track::new(STEPS_COUNT);
+        melody.mode = Mode::Cv;
+        melody.steps[0] = Step::from_midi(60, 100);
+        melody.steps[3] = Step::from_midi(67, 1);
+        melody.steps[7] = Step::from_midi(127, 127);
+        let mut drums = Track::new(STEPS_COUNT);
+        drums.steps[2] = Step::from_midi(36, 64);
+        drums.steps[6] = Step::from_midi(36, 90);
+        let tracks = vec![(0, melody), (5, drums)];
+
+        for gate in [1, 50, 100] {
+            let bytes = encode(&tracks, &SIXTEENTHS, gate, 1).unwrap();
+            let read = decode(&bytes, STEPS_COUNT, &SIXTEENTHS).unwrap();
+            let expected: Vec<Track> = tracks.iter().map(|(_, track)| track.clone()).collect();
+            assert_eq!(read, expected);
+        }
+
+        // transposed notes are written, repeats are past the steps read
+        let mut transposed = tracks.clone();
+        transposed[0].1.transpose = 2;
+        let bytes = encode(&transposed, &SIXTEENTHS, 50, 4).unwrap();
+        let read = decode(&bytes, STEPS_COUNT, &SIXTEENTHS).unwrap();
+        assert_eq!(read[0].steps[0], Step::from_midi(62, 100));
+        assert_eq!(read[0].steps[7], Step::from_midi(127, 127));
+        assert_eq!(read[1], tracks[1].1);
+    }
+
+    #[test]
+    fn quantization() {
+        // 24 ticks per sixteenth note, on a single track split by channel
+        let bytes = smf_bytes(vec![vec![
+            note_on(11, 0, 60, 100),
+            // a note on of velocity 0 is a note off
+            note_on(0, 0, 72, 0),
+            // rounded to the second step, with a higher note on the channel 3
+            note_on(2, 0, 62, 100),
+            note_on(0, 2, 48, 80),
+            note_on(0, 0, 64, 100),
+            note_on(0, 0, 61, 100),
+            // last step, then past the steps
+            note_on(155, 0, 65, 100),
+            note_on(12, 0, 67, 100),
+        ]]);
+        let tracks = decode(&bytes, STEPS_COUNT, &SIXTEENTHS).unwrap();
+        assert_eq!(tracks.len(), 2);
+
+        let mut first = Track::new(STEPS_COUNT);
+        first.mode = Mode::Cv;
+        first.steps[0] = Step::from_midi(60, 100);
+        first.steps[1] = Step::from_midi(64, 100);
+        first.steps[7] = Step::from_midi(65, 100);
+        assert_eq!(tracks[0], first);
+        let mut second = Track::new(STEPS_COUNT);
+        second.steps[1] = Step::from_midi(48, 80);
+        assert_eq!(tracks[1], second);
+
+        // quarter notes, 4 steps
+        let quarters = Timings {
+            bpm: 0,
+            division: 4,
+        };
+        let tracks = decode(&bytes, 4, &quarters).unwrap();
+        assert_eq!(tracks[0].steps.len(), 4);
+        assert_eq!(tracks[0].steps[0], Step::from_midi(64, 100));
+        assert_eq!(tracks[0].steps[2], Step::from_midi(67, 100));
+    }
+
+    #[test]
+    fn read_errors() {
+        let bytes = smf_bytes(vec![vec![note_on(0, 0, 60, 0)], vec![]]);
+        assert!(decode(&bytes, STEPS_COUNT, &SIXTEENTHS).is_err());
+        assert!(decode(b"MThd", STEPS_COUNT, &SIXTEENTHS).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+// framing of the remote control protocol, see the "Remote control protocol"
+// section of the README:
+//
+//   00 <COBS encoded payload> 00
+//   payload: <command> <data...> <CRC-16 CCITT, little endian>
+
+pub const DELIMITER: u8 = 0x00;
+
+const CRC_SIZE: usize = 2;
+
+// CRC-16 CCITT (polynomial 0x1021, initial value 0xFFFF)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// frame a payload, adding its CRC and the delimiters
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut data = payload.to_vec();
+    data.extend_from_slice(&crc16(payload).to_le_bytes());
+
+    let mut frame = vec![DELIMITER];
+    frame.extend(cobs_encode(&data));
+    frame.push(DELIMITER);
+    frame
+}
+
+// decode the bytes found between two delimiters, return the payload without
+// its CRC or None if the frame is invalid
+pub fn decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = cobs_decode(bytes)?;
+    if payload.len() <= CRC_SIZE {
+        return None;
+    }
+    let len = payload.len() - CRC_SIZE;
+    let crc = u16::from_le_bytes([payload[len], payload[len + 1]]);
+    if crc16(&payload[..len]) != crc {
+        return None;
+    }
+    payload.truncate(len);
+    Some(payload)
+}
+
+// consistent overhead byte stuffing
+fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0];
+    let mut code_index = 0;
+    let mut code = 1;
+    for byte in data {
+        if *byte != 0 {
+            out.push(*byte);
+            code += 1;
+        }
+        if *byte == 0 || code == 0xFF {
+            out[code_index] = code;
+            code_index = out.len();
+            code = 1;
+            out.push(0);
+        }
+    }
+    out[code_index] = code;
+    out
+}
+
+fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let code = data[i] as usize;
+        let end = i + code;
+        if code == 0 || end > data.len() {
+            return None;
+        }
+        out.extend_from_slice(&data[i + 1..end]);
+        if code < 0xFF && end < data.len() {
+            out.push(0);
+        }
+        i = end;
+    }
+    Some(out)
+}
+
+// split a byte stream into frames. Bytes outside of frames are console text
+// and are ignored, as are invalid frames
+#[derive(Clone, Debug, Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+    receiving: bool,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader::default()
+    }
+
+    // feed a received byte, return the payload once a valid frame is complete
+    pub fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        if byte != DELIMITER {
+            if self.receiving {
+                self.buffer.push(byte);
+            }
+            return None;
+        }
+        if !self.receiving || self.buffer.is_empty() {
+            self.receiving = true;
+            return None;
+        }
+        self.receiving = false;
+        let bytes = std::mem::take(&mut self.buffer);
+        decode(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // frames sent by the firmware (src/remote.rs): an ack of SET_STEP and the
+    // playhead of 8 tracks, whose first cursor is a zero byte
+    const ACK_FRAME: [u8; 7] = [0x00, 0x05, 0x40, 0x02, 0x81, 0x30, 0x00];
+    const PLAYHEAD_FRAME: [u8; 14] = [
+        0x00, 0x02, 0xC0, 0x0A, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0xAA, 0xDE, 0x00,
+    ];
+
+    fn read(reader: &mut FrameReader, bytes: &[u8]) -> Vec<Vec<u8>> {
+        bytes.iter().filter_map(|byte| reader.push(*byte)).collect()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+
+    #[test]
+    fn firmware_frames() {
+        assert_eq!(encode(&[0x40, 0x02]), ACK_FRAME);
+        assert_eq!(encode(&[0xC0, 0, 1, 2, 3, 4, 5, 6, 7]), PLAYHEAD_FRAME);
+        assert_eq!(decode(&ACK_FRAME[1..6]), Some(vec![0x40, 0x02]));
+    }
+
+    #[test]
+    fn encode_decode() {
+        let payloads = [
+            vec![0x01],
+            vec![0x00, 0x00, 0x00],
+            (0..=255).collect(),
+            vec![0x55; 254],
+            vec![0x55; 600],
+        ];
+        for payload in payloads {
+            let frame = encode(&payload);
+            assert_eq!(frame[0], DELIMITER);
+            assert_eq!(frame[frame.len() - 1], DELIMITER);
+            assert!(!frame[1..frame.len() - 1].contains(&DELIMITER));
+            assert_eq!(decode(&frame[1..frame.len() - 1]), Some(payload));
+        }
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut frame = ACK_FRAME;
+        frame[3] = 0x03;
+        assert_eq!(decode(&frame[1..6]), None);
+        // code pointing past the end of the frame
+        assert_eq!(decode(&[0x07, 0x40, 0x02]), None);
+        // only a CRC
+        assert_eq!(decode(&encode(&[])[1..4]), None);
+    }
+
+    #[test]
+    fn reader() {
+        let mut reader = FrameReader::new();
+        let mut bytes = b"> console text\n".to_vec();
+        bytes.extend_from_slice(&ACK_FRAME);
+        // consecutive delimiters
+        bytes.push(DELIMITER);
+        bytes.extend_from_slice(&PLAYHEAD_FRAME);
+        let mut invalid = ACK_FRAME;
+        invalid[4] ^= 0xFF;
+        bytes.extend_from_slice(&invalid);
+        bytes.extend_from_slice(&ACK_FRAME);
+        assert_eq!(
+            read(&mut reader, &bytes),
+            [
+                vec![0x40, 0x02],
+                vec![0xC0, 0, 1, 2, 3, 4, 5, 6, 7],
+                vec![0x40, 0x02]
+            ]
+        );
+    }
+}
@@ -0,0 +1,660 @@
+//! Client side of the sequencer remote control protocol.
+//!
+//! The protocol runs on the console serial port, next to the text console.
+//! Requests are answered in order, playhead events are sent on every step
+//! once subscribed:
+//!
+//! ```no_run
+//! use sequencer_remote::Client;
+//!
+//! let mut client = Client::open("/dev/ttyUSB0")?;
+//! let mut step = client.get_step(0, 2)?;
+//! step.gate = true;
+//! client.set_step(0, 2, step)?;
+//!
+//! client.subscribe(true)?;
+//! loop {
+//!     let cursors = client.next_playhead()?;
+//!     println!("{:?}", cursors);
+//! }
+//! # Ok::<(), sequencer_remote::Error>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+pub mod frame;
+
+use frame::FrameReader;
+
+pub const BAUD_RATE: u32 = 115_200;
+pub const TRACKS_COUNT: usize = 8;
+
+// requests
+const GET_STEP: u8 = 0x01;
+const SET_STEP: u8 = 0x02;
+const GET_TRACK: u8 = 0x03;
+const SET_TRACK: u8 = 0x04;
+const GET_SETTINGS: u8 = 0x05;
+const SET_SETTINGS: u8 = 0x06;
+const SUBSCRIBE: u8 = 0x07;
+const TRANSPORT: u8 = 0x08;
+
+// replies and events
+const REPLY: u8 = 0x80;
+const ACK: u8 = 0x40;
+const NAK: u8 = 0x41;
+const PLAYHEAD: u8 = 0xC0;
+const REPLY_GET_STEP: u8 = REPLY | GET_STEP;
+const REPLY_GET_TRACK: u8 = REPLY | GET_TRACK;
+const REPLY_GET_SETTINGS: u8 = REPLY | GET_SETTINGS;
+
+// size of a step sent by the sequencer
+const STEP_SIZE: usize = 12;
+// size of the track settings preceding the steps
+const TRACK_HEADER_SIZE: usize = 8;
+// swing of the tracks using the swing of the settings
+const NO_SWING: u8 = 0xFF;
+// playhead events kept while waiting for a reply
+const EVENTS_SIZE: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Gate,
+    Cv,
+    Velocity,
+}
+
+// condition for a step to be played
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Always,
+    // first cycle only
+    First,
+    // the a-th cycle of every b cycles
+    Cycle(u8, u8),
+    // the last conditional step of the track was played, or was not
+    Previous,
+    NotPrevious,
+    // fill mode is on, or off
+    Fill,
+    NotFill,
+}
+
+impl Condition {
+    fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Condition::Always => [0, 0],
+            Condition::First => [1, 0],
+            Condition::Cycle(a, b) => [2, a << 4 | b],
+            Condition::Previous => [3, 0],
+            Condition::NotPrevious => [4, 0],
+            Condition::Fill => [5, 0],
+            Condition::NotFill => [6, 0],
+        }
+    }
+
+    fn from_bytes(bytes: [u8; 2]) -> Option<Condition> {
+        Some(match bytes[0] {
+            0 => Condition::Always,
+            1 => Condition::First,
+            2 => Condition::Cycle(bytes[1] >> 4, bytes[1] & 0x0F),
+            3 => Condition::Previous,
+            4 => Condition::NotPrevious,
+            5 => Condition::Fill,
+            6 => Condition::NotFill,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    pub gate: bool,
+    // semitone offset from C
+    pub note: u8,
+    // octave 0 is the octave of middle C
+    pub octave: i8,
+    pub velocity: u8,
+    // hold the note until the next step
+    pub tie: bool,
+    // gate length in percent of the step, 0 for the gate length of the
+    // settings
+    pub gate_length: u8,
+    // number of gates evenly spread over the step, 1 to 8
+    pub ratchets: u8,
+    // chance of the step to be played, in percent
+    pub probability: u8,
+    pub condition: Condition,
+    // glide from the previous note to the note of the step
+    pub slide: bool,
+    // micro-timing offset from the grid, in percent of a step
+    pub nudge: i8,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Step {
+            gate: false,
+            note: 0,
+            octave: 0,
+            velocity: 255,
+            tie: false,
+            gate_length: 0,
+            ratchets: 1,
+            probability: 100,
+            condition: Condition::Always,
+            slide: false,
+            nudge: 0,
+        }
+    }
+}
+
+impl Step {
+    fn to_bytes(self) -> [u8; STEP_SIZE] {
+        let condition = self.condition.to_bytes();
+        [
+            self.gate as u8,
+            self.note,
+            self.octave as u8,
+            self.velocity,
+            self.tie as u8,
+            self.gate_length,
+            self.ratchets,
+            self.probability,
+            condition[0],
+            condition[1],
+            self.slide as u8,
+            self.nudge as u8,
+        ]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Step> {
+        if bytes.len() < 4 || bytes[0] > 1 || bytes[1] > 11 {
+            return None;
+        }
+        Some(Step {
+            gate: bytes[0] == 1,
+            note: bytes[1],
+            octave: bytes[2] as i8,
+            velocity: bytes[3],
+            tie: bytes.get(4) == Some(&1),
+            gate_length: bytes.get(5).copied().unwrap_or(0),
+            ratchets: bytes.get(6).copied().unwrap_or(1),
+            probability: bytes.get(7).copied().unwrap_or(100),
+            condition: match bytes.get(8..10) {
+                Some(condition) => Condition::from_bytes([condition[0], condition[1]])?,
+                None => Condition::Always,
+            },
+            slide: bytes.get(10) == Some(&1),
+            nudge: bytes.get(11).map_or(0, |nudge| *nudge as i8),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    pub mode: Mode,
+    // number of steps played
+    pub length: u8,
+    // semitones
+    pub transpose: i8,
+    // glide time of the sliding steps in milliseconds
+    pub glide: u16,
+    // percentage of a step, None for the swing of the settings
+    pub swing: Option<u8>,
+    pub steps: Vec<Step>,
+}
+
+impl Track {
+    // same record as the SysEx pattern dumps, without the track index
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            match self.mode {
+                Mode::Gate => 0,
+                Mode::Cv => 1,
+                Mode::Velocity => 2,
+            },
+            self.length,
+            self.transpose as u8,
+            self.steps.len() as u8,
+            STEP_SIZE as u8,
+        ];
+        bytes.extend_from_slice(&self.glide.to_le_bytes());
+        bytes.push(self.swing.unwrap_or(NO_SWING));
+        for step in &self.steps {
+            bytes.extend_from_slice(&step.to_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Track> {
+        let (header, data) = bytes.split_at_checked(TRACK_HEADER_SIZE)?;
+        let mode = match header[0] {
+            0 => Mode::Gate,
+            1 => Mode::Cv,
+            2 => Mode::Velocity,
+            _ => return None,
+        };
+        let (count, size) = (header[3] as usize, header[4] as usize);
+        if size < 4 || data.len() < count * size {
+            return None;
+        }
+        let steps = data
+            .chunks(size)
+            .take(count)
+            .map(Step::from_bytes)
+            .collect::<Option<Vec<_>>>()?;
+        Some(Track {
+            mode,
+            length: header[1],
+            transpose: header[2] as i8,
+            glide: u16::from_le_bytes([header[5], header[6]]),
+            swing: match header[7] {
+                NO_SWING => None,
+                swing => Some(swing),
+            },
+            steps,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub bpm: u16,
+    // percentage of the step length
+    pub gate_length: u8,
+    // track index, starting at 0
+    pub current_track: u8,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Stop,
+    // restart all the tracks from their first step
+    Start,
+    // resume all the tracks from their current step
+    Continue,
+}
+
+// error codes of the sequencer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RemoteError {
+    Malformed,
+    UnknownCommand,
+    InvalidArgument,
+    Busy,
+    Unknown(u8),
+}
+
+impl From<u8> for RemoteError {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => RemoteError::Malformed,
+            2 => RemoteError::UnknownCommand,
+            3 => RemoteError::InvalidArgument,
+            4 => RemoteError::Busy,
+            code => RemoteError::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // the sequencer rejected the request
+    Rejected(RemoteError),
+    // the reply does not match the request
+    UnexpectedReply,
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{}", error),
+            Error::Rejected(error) => write!(f, "request rejected: {:?}", error),
+            Error::UnexpectedReply => write!(f, "unexpected reply"),
+            Error::Timeout => write!(f, "no reply from the sequencer"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(error),
+        }
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(error: serialport::Error) -> Self {
+        Error::Io(error.into())
+    }
+}
+
+/// Remote control client, over any byte stream connected to the console port.
+pub struct Client<P> {
+    port: P,
+    reader: FrameReader,
+    // playhead events received while waiting for replies
+    events: VecDeque<[u8; TRACKS_COUNT]>,
+}
+
+impl Client<Box<dyn serialport::SerialPort>> {
+    /// Open the console serial port of the sequencer.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let port = serialport::new(path, BAUD_RATE)
+            .timeout(Duration::from_secs(1))
+            .open()?;
+        Ok(Client::new(port))
+    }
+}
+
+impl<P: Read + Write> Client<P> {
+    /// Reads are expected to time out, otherwise a lost reply blocks forever.
+    pub fn new(port: P) -> Self {
+        Client {
+            port,
+            reader: FrameReader::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    pub fn get_step(&mut self, track: u8, step: u8) -> Result<Step, Error> {
+        let reply = self.request(&[GET_STEP, track, step])?;
+        match reply.as_slice() {
+            [REPLY_GET_STEP, _, _, bytes @ ..] => {
+                Step::from_bytes(bytes).ok_or(Error::UnexpectedReply)
+            }
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    pub fn set_step(&mut self, track: u8, step: u8, value: Step) -> Result<(), Error> {
+        let mut payload = vec![SET_STEP, track, step];
+        payload.extend_from_slice(&value.to_bytes());
+        self.ack(&payload)
+    }
+
+    pub fn get_track(&mut self, track: u8) -> Result<Track, Error> {
+        let reply = self.request(&[GET_TRACK, track])?;
+        match reply.as_slice() {
+            [REPLY_GET_TRACK, _, bytes @ ..] => {
+                Track::from_bytes(bytes).ok_or(Error::UnexpectedReply)
+            }
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    pub fn set_track(&mut self, track: u8, value: &Track) -> Result<(), Error> {
+        let mut payload = vec![SET_TRACK, track];
+        payload.extend(value.to_bytes());
+        self.ack(&payload)
+    }
+
+    pub fn get_settings(&mut self) -> Result<Settings, Error> {
+        let reply = self.request(&[GET_SETTINGS])?;
+        match reply.as_slice() {
+            [REPLY_GET_SETTINGS, bpm_low, bpm_high, gate_length, current_track] => Ok(Settings {
+                bpm: u16::from_le_bytes([*bpm_low, *bpm_high]),
+                gate_length: *gate_length,
+                current_track: *current_track,
+            }),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: Settings) -> Result<(), Error> {
+        let bpm = settings.bpm.to_le_bytes();
+        self.ack(&[
+            SET_SETTINGS,
+            bpm[0],
+            bpm[1],
+            settings.gate_length,
+            settings.current_track,
+        ])
+    }
+
+    /// Enable or disable playhead events.
+    pub fn subscribe(&mut self, enabled: bool) -> Result<(), Error> {
+        self.ack(&[SUBSCRIBE, enabled as u8])
+    }
+
+    pub fn transport(&mut self, transport: Transport) -> Result<(), Error> {
+        let transport = match transport {
+            Transport::Stop => 0,
+            Transport::Start => 1,
+            Transport::Continue => 2,
+        };
+        self.ack(&[TRANSPORT, transport])
+    }
+
+    /// Wait for the next playhead event, the current step of each track.
+    pub fn next_playhead(&mut self) -> Result<[u8; TRACKS_COUNT], Error> {
+        loop {
+            if let Some(cursors) = self.events.pop_front() {
+                return Ok(cursors);
+            }
+            let payload = self.read_frame()?;
+            self.queue_event(&payload);
+        }
+    }
+
+    fn ack(&mut self, payload: &[u8]) -> Result<(), Error> {
+        match self.request(payload)?.as_slice() {
+            [ACK, command] if *command == payload[0] => Ok(()),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    // send a request and return its reply, naks are returned as errors
+    fn request(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.port.write_all(&frame::encode(payload))?;
+        self.port.flush()?;
+        loop {
+            let reply = self.read_frame()?;
+            match reply.as_slice() {
+                [PLAYHEAD, ..] => self.queue_event(&reply),
+                // rejected frames are answered with a command of 0
+                [NAK, command, code] if *command == payload[0] || *command == 0 => {
+                    return Err(Error::Rejected(RemoteError::from(*code)))
+                }
+                _ => return Ok(reply),
+            }
+        }
+    }
+
+    fn queue_event(&mut self, payload: &[u8]) {
+        if let [PLAYHEAD, cursors @ ..] = payload {
+            if let Ok(cursors) = <[u8; TRACKS_COUNT]>::try_from(cursors) {
+                // drop the oldest events when nobody reads them
+                if self.events.len() == EVENTS_SIZE {
+                    self.events.pop_front();
+                }
+                self.events.push_back(cursors);
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        let mut byte = [0];
+        loop {
+            if self.port.read(&mut byte)? == 0 {
+                return Err(Error::Timeout);
+            }
+            if let Some(payload) = self.reader.push(byte[0]) {
+                return Ok(payload);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // frames sent by the firmware (src/remote.rs)
+    const PLAYHEAD_FRAME: [u8; 14] = [
+        0x00, 0x02, 0xC0, 0x0A, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0xAA, 0xDE, 0x00,
+    ];
+    // 120 BPM, gate length of 50%, fourth track
+    const SETTINGS_FRAME: [u8; 10] = [0x00, 0x03, 0x85, 0x78, 0x05, 0x32, 0x03, 0xA9, 0x84, 0x00];
+    // GET_SETTINGS rejected with an invalid argument
+    const NAK_FRAME: [u8; 8] = [0x00, 0x06, 0x41, 0x05, 0x03, 0x97, 0x29, 0x00];
+    // step serialized by the firmware, see Step::to_bytes in src/track.rs
+    const STEP_BYTES: [u8; STEP_SIZE] = [1, 9, 0xFF, 200, 1, 50, 3, 75, 2, 0x34, 1, 0xF6];
+
+    // serial port replaying the bytes sent by the sequencer, reads time out
+    // once they are all read
+    struct Port {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Port {
+        fn new(frames: &[&[u8]]) -> Port {
+            Port {
+                input: frames.concat().into(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Port {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None => Err(io::ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn step() -> Step {
+        Step {
+            gate: true,
+            note: 9,
+            octave: -1,
+            velocity: 200,
+            tie: true,
+            gate_length: 50,
+            ratchets: 3,
+            probability: 75,
+            condition: Condition::Cycle(3, 4),
+            slide: true,
+            nudge: -10,
+        }
+    }
+
+    #[test]
+    fn step_bytes() {
+        assert_eq!(step().to_bytes(), STEP_BYTES);
+        assert_eq!(Step::from_bytes(&STEP_BYTES), Some(step()));
+        // steps of older firmwares
+        assert_eq!(
+            Step::from_bytes(&STEP_BYTES[..4]),
+            Some(Step {
+                gate: true,
+                note: 9,
+                octave: -1,
+                velocity: 200,
+                ..Step::default()
+            })
+        );
+        assert_eq!(Step::from_bytes(&[1, 12, 0, 0]), None);
+    }
+
+    #[test]
+    fn track_bytes() {
+        let track = Track {
+            mode: Mode::Velocity,
+            length: 3,
+            transpose: -7,
+            glide: 1500,
+            swing: Some(50),
+            steps: vec![step(), Step::default(), step()],
+        };
+        // same header as Track::to_bytes in src/track.rs
+        let mut bytes = track.to_bytes();
+        assert_eq!(
+            bytes[..TRACK_HEADER_SIZE],
+            [2, 3, 0xF9, 3, STEP_SIZE as u8, 0xDC, 0x05, 50]
+        );
+        assert_eq!(
+            bytes[TRACK_HEADER_SIZE..TRACK_HEADER_SIZE + STEP_SIZE],
+            STEP_BYTES
+        );
+        assert_eq!(Track::from_bytes(&bytes), Some(track.clone()));
+        bytes[7] = NO_SWING;
+        assert_eq!(Track::from_bytes(&bytes).unwrap().swing, None);
+        assert_eq!(Track::from_bytes(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn request_frames() {
+        let mut client = Client::new(Port::new(&[&frame::encode(&[ACK, SET_STEP])]));
+        client.set_step(1, 2, step()).unwrap();
+        let mut expected = vec![SET_STEP, 1, 2];
+        expected.extend_from_slice(&STEP_BYTES);
+        assert_eq!(client.into_inner().output, frame::encode(&expected));
+    }
+
+    #[test]
+    fn replies_and_events() {
+        let port = Port::new(&[b"> text", &PLAYHEAD_FRAME, &SETTINGS_FRAME, &NAK_FRAME]);
+        let mut client = Client::new(port);
+        assert_eq!(
+            client.get_settings().unwrap(),
+            Settings {
+                bpm: 120,
+                gate_length: 50,
+                current_track: 3,
+            }
+        );
+        assert!(matches!(
+            client.get_settings(),
+            Err(Error::Rejected(RemoteError::InvalidArgument))
+        ));
+        // the event received while waiting for the first reply
+        assert_eq!(client.next_playhead().unwrap(), [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(matches!(client.next_playhead(), Err(Error::Timeout)));
+        // the ack does not match the request
+        let mut client = Client::new(Port::new(&[&frame::encode(&[ACK, SET_STEP])]));
+        assert!(matches!(
+            client.subscribe(true),
+            Err(Error::UnexpectedReply)
+        ));
+    }
+}